@@ -0,0 +1,210 @@
+use std::time::Instant;
+
+use openrgb2::Color;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::{
+    colorspace::to_rgb,
+    config::{current_config, ColorRef, Theme, TransitionProfile},
+    consts::*,
+    easing::Easing,
+    utils::{lerp_color, WideColor, ZonedControllerInfo},
+};
+
+// Startup and shutdown animations. Each one is drawn as an overlay that covers the device at progress 0
+// and reveals it at 1, the startup plays it forwards and the shutdown backwards
+#[derive(Deserialize, Clone)]
+#[serde(tag = "animation", rename_all = "snake_case")]
+pub enum AnimationConfig {
+    None,
+    Ripple(RippleAnimation),
+    Static(StaticAnimation),
+    Fade(FadeAnimation),
+    Sweep(SweepAnimation),
+}
+
+// A bright ring spreading from the center, leaving the fill color behind that fades into the device
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RippleAnimation {
+    pub duration_ms: u32,
+    pub easing: Easing,
+    pub color: ColorRef,
+    pub fill: ColorRef,
+}
+
+impl Default for RippleAnimation {
+    fn default() -> Self {
+        RippleAnimation {
+            duration_ms: 5000,
+            easing: Easing::Linear,
+            color: ColorRef::Literal(WHITE),
+            fill: ColorRef::Theme("gray".to_owned()),
+        }
+    }
+}
+
+// Random noise
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticAnimation {
+    pub duration_ms: u32,
+    pub easing: Easing,
+    pub color: ColorRef,
+}
+
+impl Default for StaticAnimation {
+    fn default() -> Self {
+        StaticAnimation {
+            duration_ms: 1800,
+            easing: Easing::Linear,
+            color: ColorRef::Literal(RED),
+        }
+    }
+}
+
+// From or to black
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FadeAnimation {
+    pub duration_ms: u32,
+    pub easing: Easing,
+}
+
+impl Default for FadeAnimation {
+    fn default() -> Self {
+        FadeAnimation {
+            duration_ms: 1000,
+            easing: Easing::Linear,
+        }
+    }
+}
+
+// A bar moving from left to right, black on one side of it
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SweepAnimation {
+    pub duration_ms: u32,
+    pub easing: Easing,
+    pub color: ColorRef,
+    // Leds
+    pub width: f64,
+}
+
+impl Default for SweepAnimation {
+    fn default() -> Self {
+        SweepAnimation {
+            duration_ms: 1500,
+            easing: Easing::EaseInOut,
+            color: ColorRef::Literal(WHITE),
+            width: 2.0,
+        }
+    }
+}
+
+impl AnimationConfig {
+    pub fn timing(&self) -> Option<TransitionProfile> {
+        let (duration_ms, easing) = match self {
+            AnimationConfig::None => return None,
+            AnimationConfig::Ripple(ripple) => (ripple.duration_ms, ripple.easing),
+            AnimationConfig::Static(noise) => (noise.duration_ms, noise.easing),
+            AnimationConfig::Fade(fade) => (fade.duration_ms, fade.easing),
+            AnimationConfig::Sweep(sweep) => (sweep.duration_ms, sweep.easing),
+        };
+        Some(TransitionProfile::new(duration_ms, easing))
+    }
+
+    // Color and alpha of every led at a moment of the animation (eased progress)
+    pub fn overlay(
+        &self,
+        device: &ZonedControllerInfo,
+        theme: &Theme,
+        progress: f64,
+    ) -> Vec<(Color, f64)> {
+        let progress = progress.clamp(0.0, 1.0);
+        let mut rng = rand::thread_rng();
+        (0..device.total_leds)
+            .map(|index| {
+                let (x, _) = device.position(index);
+                match self {
+                    AnimationConfig::None => (BLACK, 0.0),
+                    AnimationConfig::Ripple(ripple) => {
+                        let radius = progress * device.center().0 * 3.0;
+                        let distance = device.distance_to(index, device.center());
+                        if distance < radius {
+                            // 7 led offset from the center, 4 led width (offset from the edge)
+                            let fill = ((distance - radius + 7.0) / 4.0).clamp(0.0, 1.0);
+                            (ripple.fill.resolve(theme), fill)
+                        } else {
+                            let front = (distance - radius) / 2.0;
+                            (lerp_color(&ripple.color.resolve(theme), &BLACK, front), 1.0)
+                        }
+                    }
+                    AnimationConfig::Static(noise) => {
+                        // Strongest halfway through
+                        let strength = (4.0 * progress * (1.0 - progress)).min(1.0);
+                        let brightness = rng.gen::<f64>() * strength;
+                        let color = lerp_color(&BLACK, &noise.color.resolve(theme), brightness);
+                        (color, 1.0 - progress)
+                    }
+                    AnimationConfig::Fade(_) => (BLACK, 1.0 - progress),
+                    AnimationConfig::Sweep(sweep) => {
+                        let front = progress * (device.size().0 + sweep.width) - sweep.width;
+                        if x < front {
+                            (BLACK, 0.0)
+                        } else if x < front + sweep.width {
+                            (sweep.color.resolve(theme), 1.0)
+                        } else {
+                            (BLACK, 1.0)
+                        }
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnimationsConfig {
+    pub startup: AnimationConfig,
+    pub shutdown: AnimationConfig,
+}
+
+impl Default for AnimationsConfig {
+    fn default() -> Self {
+        AnimationsConfig {
+            startup: AnimationConfig::Ripple(RippleAnimation::default()),
+            shutdown: AnimationConfig::Static(StaticAnimation::default()),
+        }
+    }
+}
+
+// Draw the shutdown animation that started at some point over a frame of a device, black once it's over
+pub fn cover_with_shutdown(
+    device: &ZonedControllerInfo,
+    frame: &mut [WideColor],
+    started: Instant,
+) {
+    let shutdown = current_config().animations.shutdown.clone();
+    let progress = match shutdown.timing() {
+        Some(timing) if timing.duration_ms > 0 => {
+            let elapsed = started.elapsed().as_secs_f64() * 1000.0 / timing.duration_ms as f64;
+            1.0 - timing.easing.apply(elapsed)
+        }
+        // Nothing to play, just turn off
+        _ => 0.0,
+    };
+    let overlay = if progress > 0.0 {
+        shutdown.overlay(device, &THEME.read().unwrap(), progress)
+    } else {
+        vec![(BLACK, 1.0); device.total_leds]
+    };
+
+    let space = *COLOR_SPACE.read().unwrap();
+    for (pixel, (color, alpha)) in frame.iter_mut().zip(overlay) {
+        let below = [pixel.r, pixel.g, pixel.b].map(|channel| channel / 255.0);
+        *pixel = WideColor::from_rgb(space.mix(below, to_rgb(&color), alpha));
+    }
+}
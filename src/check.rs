@@ -0,0 +1,212 @@
+use std::{error::Error, path::Path, sync::Arc};
+
+use openrgb2::Controller;
+
+use crate::{
+    config::{load_config, Config, DeviceConfig, DeviceRole},
+    geometry::{geometry_path, PhysicalLayout},
+    utils::{connect_openrgb, layout_warnings, try_connect_openrgb, ZonedControllerInfo},
+};
+
+#[derive(Default)]
+struct Report {
+    problems: usize,
+    warnings: usize,
+}
+
+impl Report {
+    fn ok(&self, message: impl AsRef<str>) {
+        println!("  ok       {}", message.as_ref());
+    }
+
+    fn warning(&mut self, message: impl AsRef<str>) {
+        self.warnings += 1;
+        println!("  warning  {}", message.as_ref());
+    }
+
+    fn problem(&mut self, message: impl AsRef<str>) {
+        self.problems += 1;
+        println!("  problem  {}", message.as_ref());
+    }
+}
+
+// Find the controller and zone of a device like the daemon does on startup.
+// Only the keyboard has to be there, the other devices are skipped if they are missing
+fn check_device(
+    report: &mut Report,
+    device: &DeviceConfig,
+    controllers: &[(Option<String>, Arc<Controller>)],
+    config_path: &Path,
+) -> Option<ZonedControllerInfo> {
+    // Only the controllers of the device's server
+    let controllers: Vec<&Arc<Controller>> = controllers
+        .iter()
+        .filter(|(server, _)| *server == device.server)
+        .map(|(_, controller)| controller)
+        .collect();
+    let role = match device.role {
+        DeviceRole::Keyboard => "keyboard",
+        DeviceRole::AmbientStrip => "ambient strip",
+        DeviceRole::Mirror => "mirror",
+        DeviceRole::Off => "off",
+    };
+    let Some(controller) = controllers
+        .iter()
+        .find(|controller| controller.name().eq(&device.name))
+    else {
+        let message = format!(
+            "{role}: controller '{}' not found, available: {}",
+            device.name,
+            controllers
+                .iter()
+                .map(|controller| format!("'{}'", controller.name()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        match device.role {
+            DeviceRole::Keyboard => report.problem(message),
+            _ => report.warning(message),
+        }
+        return None;
+    };
+
+    let zones: Vec<String> = controller
+        .get_all_zones()
+        .map(|zone| format!("'{}'", zone.name()))
+        .collect();
+    match ZonedControllerInfo::new(Arc::clone(controller), &device.zone) {
+        Ok(info) => {
+            report.ok(format!(
+                "{role}: '{}' zone '{}', {} leds ({}x{})",
+                device.name, device.zone, info.total_leds, info.width, info.height
+            ));
+            if let Some(path) = geometry_path(device, config_path) {
+                match PhysicalLayout::load(&path) {
+                    Ok(layout) => {
+                        let names = info.leds().map(|(_, led)| led.name.as_str());
+                        let placed = layout.place(names).iter().flatten().count();
+                        let message = format!(
+                            "{role}.geometry places {placed} of {} leds",
+                            info.total_leds
+                        );
+                        match placed {
+                            0 => report.problem(message),
+                            placed if placed < info.total_leds => report.warning(message),
+                            _ => report.ok(message),
+                        }
+                    }
+                    Err(e) => report.problem(format!("{role}.geometry: {e}")),
+                }
+            }
+            Some(info)
+        }
+        Err(e) => {
+            let message = format!("{role}: {e}, available zones: {}", zones.join(", "));
+            match device.role {
+                DeviceRole::Keyboard => report.problem(message),
+                _ => report.warning(message),
+            }
+            None
+        }
+    }
+}
+
+fn check_keyboard_layouts(report: &mut Report, config: &Config, keyboard: &ZonedControllerInfo) {
+    let layouts = [("layout".to_owned(), &config.layout)].into_iter().chain(
+        config.profiles.iter().filter_map(|(name, profile)| {
+            Some((format!("profiles.{name}.layout"), profile.layout.as_ref()?))
+        }),
+    );
+
+    let names: Vec<&str> = keyboard.leds().map(|(_, led)| led.name.as_str()).collect();
+    for (path, layout) in layouts {
+        for (substrate_name, substrate) in [("active", &layout.active), ("idle", &layout.idle)] {
+            for (i, group) in substrate.keys.iter().enumerate() {
+                let count = group.keys.resolve(names.iter().copied()).len();
+                report.ok(format!(
+                    "{path}.{substrate_name}.keys[{i}] selects {count} leds"
+                ));
+            }
+        }
+        let markers = layout.language_markers.resolve(names.iter().copied()).len();
+        report.ok(format!("{path}.language_markers selects {markers} leds"));
+
+        for warning in layout_warnings(keyboard, layout) {
+            report.warning(format!("{path}.{warning}"));
+        }
+    }
+
+    let top_bar_config = &config.keyboard().top_bar;
+    let top_bar = keyboard.top_bar(top_bar_config.offset_start, top_bar_config.offset_end);
+    if top_bar.is_empty() {
+        report.warning("keyboard.top_bar: the offsets leave no leds for the progress bar");
+    } else {
+        report.ok(format!("keyboard.top_bar has {} leds", top_bar.len()));
+    }
+}
+
+// Validate the config and its devices without changing anything on them, returns whether it's usable
+pub async fn check_config(config_path: &Path) -> Result<bool, Box<dyn Error>> {
+    let mut report = Report::default();
+    println!("Checking {}", config_path.display());
+
+    // Colors are parsed (and rejected if invalid) while loading
+    let config = match load_config(config_path) {
+        Ok(config) => {
+            report.ok("config file is valid");
+            config
+        }
+        Err(e) => {
+            println!("{e}");
+            return Ok(false);
+        }
+    };
+
+    let openrgb = &config.openrgb;
+    let client = connect_openrgb(&openrgb.address(), &openrgb.client_name).await?;
+    let mut controllers: Vec<(Option<String>, Arc<Controller>)> = client
+        .get_all_controllers()
+        .await?
+        .into_iter()
+        .map(|controller| (None, Arc::new(controller)))
+        .collect();
+    for (name, server) in &openrgb.servers {
+        let found = match try_connect_openrgb(&server.address(), &openrgb.client_name).await {
+            Ok(client) => client
+                .get_all_controllers()
+                .await
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match found {
+            Ok(found) => {
+                report.ok(format!("server '{name}' at {}", server.address()));
+                controllers.extend(
+                    found
+                        .into_iter()
+                        .map(|controller| (Some(name.clone()), Arc::new(controller))),
+                );
+            }
+            // The daemon skips its devices until it's back
+            Err(e) => report.warning(format!("server '{name}': {e}")),
+        }
+    }
+
+    let mut keyboard = None;
+    for device in &config.devices {
+        let info = check_device(&mut report, device, &controllers, config_path);
+        if device.role == DeviceRole::Keyboard {
+            keyboard = info;
+        }
+    }
+
+    if let Some(keyboard) = keyboard {
+        check_keyboard_layouts(&mut report, &config, &keyboard);
+    }
+
+    println!(
+        "{} problem(s), {} warning(s)",
+        report.problems, report.warnings
+    );
+    Ok(report.problems == 0)
+}
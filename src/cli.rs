@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+#[derive(Parser)]
+#[command(
+    version,
+    about = "Displays notifications and progress on OpenRGB keyboards"
+)]
+pub struct Cli {
+    /// Config file to use instead of $XDG_CONFIG_HOME/keyboard_vis/config.json or /etc/keyboard_vis/config.json
+    #[arg(long, value_name = "PATH", global = true)]
+    pub config: Option<PathBuf>,
+
+    /// Print the path of the config file that is used and exit
+    #[arg(long)]
+    pub print_config_path: bool,
+
+    /// Put the devices back how they were before the daemon took them over (after a crash) and exit
+    #[arg(long)]
+    pub restore: bool,
+
+    /// Don't play the startup animation
+    #[arg(long)]
+    pub skip_intro: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Validate the config against the devices of the running OpenRGB server and exit
+    CheckConfig,
+}
@@ -0,0 +1,113 @@
+use openrgb2::Color;
+use serde::Deserialize;
+
+// Channels of a color from 0 to 1, gamma encoded like the Color they came from
+pub type Rgb = [f64; 3];
+
+// What interpolation happens in, srgb is the old behavior (straight on the bytes)
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorSpace {
+    Srgb,
+    // Linear light, fades and dimming keep their brightness
+    Linear,
+    // Perceptual, fades between saturated colors don't go gray
+    #[default]
+    Oklab,
+}
+
+pub fn to_rgb(color: &Color) -> Rgb {
+    [color.r, color.g, color.b].map(|channel| channel as f64 / 255.0)
+}
+
+pub fn to_color(rgb: Rgb) -> Color {
+    let [r, g, b] = rgb.map(|channel| (channel.clamp(0.0, 1.0) * 255.0).round() as u8);
+    Color { r, g, b }
+}
+
+pub fn srgb_to_linear(channel: f64) -> f64 {
+    if channel <= 0.04045 {
+        channel / 12.92
+    } else {
+        ((channel + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(channel: f64) -> f64 {
+    let channel = channel.clamp(0.0, 1.0);
+    if channel <= 0.0031308 {
+        channel * 12.92
+    } else {
+        1.055 * channel.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Scale the brightness of a color, always in linear light: mixing towards black in OKLab (or on the 8 bit values)
+// makes dim levels fall off much faster than the brightness says
+pub fn dim(rgb: Rgb, brightness: f64) -> Rgb {
+    rgb.map(|channel| linear_to_srgb(srgb_to_linear(channel) * brightness.clamp(0.0, 1.0)))
+}
+
+// https://bottosson.github.io/posts/oklab/
+fn linear_to_oklab([r, g, b]: Rgb) -> [f64; 3] {
+    let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+    let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+    let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+    [
+        0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+        1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+        0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+    ]
+}
+
+fn oklab_to_linear([lightness, a, b]: [f64; 3]) -> Rgb {
+    let l = (lightness + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+    let m = (lightness - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+    let s = (lightness - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+    [
+        4.0767416621 * l - 3.3077115913 * m + 0.2309699292 * s,
+        -1.2684380046 * l + 2.6097574011 * m - 0.3413193965 * s,
+        -0.0041960863 * l - 0.7034186147 * m + 1.7076147010 * s,
+    ]
+}
+
+impl ColorSpace {
+    // Convert sRGB channels into the space
+    pub fn encode(&self, rgb: Rgb) -> [f64; 3] {
+        match self {
+            ColorSpace::Srgb => rgb,
+            ColorSpace::Linear => rgb.map(srgb_to_linear),
+            ColorSpace::Oklab => linear_to_oklab(rgb.map(srgb_to_linear)),
+        }
+    }
+
+    // Convert a value of the space back into sRGB channels
+    pub fn decode(&self, value: [f64; 3]) -> Rgb {
+        match self {
+            ColorSpace::Srgb => value.map(|channel| channel.clamp(0.0, 1.0)),
+            ColorSpace::Linear => value.map(linear_to_srgb),
+            ColorSpace::Oklab => oklab_to_linear(value).map(linear_to_srgb),
+        }
+    }
+
+    pub fn mix(&self, from: Rgb, to: Rgb, progress: f64) -> Rgb {
+        if *self == ColorSpace::Srgb {
+            return [0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * progress);
+        }
+        let (from, to) = (self.encode(from), self.encode(to));
+        self.decode([0, 1, 2].map(|c| from[c] + (to[c] - from[c]) * progress))
+    }
+
+    pub fn lerp(&self, from: &Color, to: &Color, progress: f64) -> Color {
+        let progress_01 = progress.clamp(0.0, 1.0);
+        match self {
+            // Kept exactly as it always was for comparison
+            ColorSpace::Srgb => Color {
+                r: (from.r as f64 * (1.0 - progress_01) + to.r as f64 * progress_01) as u8,
+                g: (from.g as f64 * (1.0 - progress_01) + to.g as f64 * progress_01) as u8,
+                b: (from.b as f64 * (1.0 - progress_01) + to.b as f64 * progress_01) as u8,
+            },
+            _ => to_color(self.mix(to_rgb(from), to_rgb(to), progress_01)),
+        }
+    }
+}
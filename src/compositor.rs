@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use openrgb2::Color;
+use serde::Deserialize;
+
+use crate::colorspace::{linear_to_srgb, srgb_to_linear, to_rgb, ColorSpace, Rgb};
+use crate::config::TransitionProfile;
+use crate::consts::{Frame, BLACK, COLOR_SPACE, KEYBOARD_LAYERS};
+use crate::easing::Easing;
+use crate::effects::Effect;
+use crate::utils::{WideColor, WideFrame, ZonedControllerInfo};
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Add,
+    Multiply,
+    Screen,
+    Max,
+}
+
+impl BlendMode {
+    // Blend modes work on light, so outside of srgb they are applied to linear values.
+    // Multiply always is, it's what dims the keyboard
+    fn blend(&self, space: ColorSpace, below: Rgb, above: Rgb) -> Rgb {
+        if space == ColorSpace::Srgb && *self != BlendMode::Multiply {
+            return [0, 1, 2].map(|c| self.blend_channel(below[c], above[c]));
+        }
+        [0, 1, 2].map(|c| {
+            linear_to_srgb(self.blend_channel(srgb_to_linear(below[c]), srgb_to_linear(above[c])))
+        })
+    }
+
+    // Blend one channel (0..1) of the layer over the one below
+    fn blend_channel(&self, below: f64, above: f64) -> f64 {
+        match self {
+            BlendMode::Normal => above,
+            BlendMode::Add => (below + above).min(1.0),
+            BlendMode::Multiply => below * above,
+            BlendMode::Screen => 1.0 - (1.0 - below) * (1.0 - above),
+            BlendMode::Max => below.max(above),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum LayerId {
+    Base,
+    // Effect of the whole device
+    Effect,
+    Idle,
+    IdleDim,
+    Progress,
+    Flash,
+    Notifications,
+    Language,
+    External(String),
+    Overlay,
+    AmbientDim,
+}
+
+impl LayerId {
+    // Paint order of the built-in layers, higher is painted later (on top)
+    pub fn default_z(&self) -> i32 {
+        match self {
+            LayerId::Base => 0,
+            LayerId::Effect => 5,
+            LayerId::Idle => 10,
+            LayerId::IdleDim => 11,
+            LayerId::Progress => 20,
+            LayerId::Flash => 30,
+            LayerId::Notifications => 40,
+            LayerId::Language => 50,
+            LayerId::External(_) => 60,
+            LayerId::Overlay => 900,
+            LayerId::AmbientDim => 1000,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct Layer {
+    pub z: i32,
+    pub opacity: f64,
+    pub blend: BlendMode,
+    // Full precision, fades are only rounded to leds in the output stage
+    pub pixels: Vec<Rgb>,
+    // Per led alpha (0 = transparent), leds past the end of the mask are transparent
+    pub mask: Vec<f64>,
+}
+
+impl Layer {
+    // Opaque layer covering every led
+    pub fn new(id: &LayerId, pixels: Frame) -> Layer {
+        let mask = vec![1.0; pixels.len()];
+        Layer::masked(id, pixels, mask)
+    }
+
+    pub fn masked(id: &LayerId, pixels: Frame, mask: Vec<f64>) -> Layer {
+        Layer {
+            z: id.default_z(),
+            opacity: 1.0,
+            blend: BlendMode::Normal,
+            pixels: pixels.iter().map(to_rgb).collect(),
+            mask,
+        }
+    }
+
+    // Layer that only covers some leds, each with its own color and alpha
+    pub fn sparse(
+        id: &LayerId,
+        total_leds: usize,
+        leds: impl Iterator<Item = (usize, Color, f64)>,
+    ) -> Layer {
+        let mut pixels = vec![BLACK; total_leds];
+        let mut mask = vec![0.0; total_leds];
+        for (index, color, alpha) in leds {
+            if index < total_leds {
+                pixels[index] = color;
+                mask[index] = alpha;
+            }
+        }
+        Layer::masked(id, pixels, mask)
+    }
+
+    // Scales the brightness of everything below in linear light, 1 = unchanged
+    pub fn dim(id: &LayerId, total_leds: usize, brightness: f64) -> Layer {
+        let level = linear_to_srgb(brightness.clamp(0.0, 1.0));
+        Layer {
+            z: id.default_z(),
+            opacity: 1.0,
+            blend: BlendMode::Multiply,
+            pixels: vec![[level; 3]; total_leds],
+            mask: vec![1.0; total_leds],
+        }
+    }
+
+    pub fn with_opacity(mut self, opacity: f64) -> Layer {
+        self.opacity = opacity.clamp(0.0, 1.0);
+        self
+    }
+
+    fn transparent(&self) -> Layer {
+        self.clone().with_opacity(0.0)
+    }
+
+    // Color of a led in the color space (premultiplied by alpha) and its alpha
+    fn premultiplied(&self, space: ColorSpace, index: usize) -> ([f64; 3], f64) {
+        let alpha = self.mask.get(index).copied().unwrap_or(0.0) * self.opacity;
+        match self.pixels.get(index) {
+            Some(pixel) => (space.encode(*pixel).map(|c| c * alpha), alpha),
+            None => ([0.0; 3], 0.0),
+        }
+    }
+
+    // In-between state of two layers, interpolated premultiplied so fading in a layer doesn't pass through black
+    fn mix(from: &Layer, to: &Layer, progress: f64, space: ColorSpace) -> Layer {
+        let total_leds = from.pixels.len().max(to.pixels.len());
+        let (pixels, mask) = (0..total_leds)
+            .map(|index| {
+                let (from_color, from_alpha) = from.premultiplied(space, index);
+                let (to_color, to_alpha) = to.premultiplied(space, index);
+                // Springy easings overshoot
+                let alpha = (from_alpha + (to_alpha - from_alpha) * progress).clamp(0.0, 1.0);
+                if alpha <= 0.0 {
+                    return ([0.0; 3], 0.0);
+                }
+                let color = [0, 1, 2]
+                    .map(|c| (from_color[c] + (to_color[c] - from_color[c]) * progress) / alpha);
+                (space.decode(color), alpha)
+            })
+            .unzip();
+        Layer {
+            z: to.z,
+            opacity: 1.0,
+            blend: to.blend,
+            pixels,
+            mask,
+        }
+    }
+}
+
+// A layer on its way from one state to another, retargeted from wherever it currently is
+struct Transition {
+    from: Layer,
+    to: Layer,
+    start: Instant,
+    duration: Duration,
+    easing: Easing,
+    // Drop the layer once it has faded out
+    removing: bool,
+}
+
+impl Transition {
+    fn progress(&self, now: Instant) -> f64 {
+        if self.duration.is_zero() {
+            return 1.0;
+        }
+        (now.saturating_duration_since(self.start).as_secs_f64() / self.duration.as_secs_f64())
+            .min(1.0)
+    }
+
+    fn current(&self, now: Instant, space: ColorSpace) -> Layer {
+        let progress = self.progress(now);
+        if progress >= 1.0 {
+            self.to.clone()
+        } else {
+            Layer::mix(&self.from, &self.to, self.easing.apply(progress), space)
+        }
+    }
+}
+
+// Every source of color on a device owns a layer, the stack is flattened into the frame that is shown.
+// Changing a layer starts a transition from its current look, so a new target never waits for an old fade
+#[derive(Default)]
+pub struct LayerStack {
+    total_leds: usize,
+    layers: HashMap<LayerId, Transition>,
+    // Layers whose pixels are redrawn by an effect every frame
+    effects: HashMap<LayerId, Box<dyn Effect>>,
+}
+
+impl LayerStack {
+    pub fn new(total_leds: usize) -> LayerStack {
+        LayerStack {
+            total_leds,
+            layers: HashMap::new(),
+            effects: HashMap::new(),
+        }
+    }
+
+    // Let an effect draw the pixels of a layer (the mask and opacity stay), None stops it
+    pub fn set_effect(&mut self, id: LayerId, effect: Option<Box<dyn Effect>>) {
+        match effect {
+            Some(effect) => self.effects.insert(id, effect),
+            None => self.effects.remove(&id),
+        };
+    }
+
+    // Draw the next frame of every effect into its layer
+    pub fn run_effects(&mut self, device: &ZonedControllerInfo, time: f64) {
+        for (id, effect) in self.effects.iter_mut() {
+            if let Some(transition) = self.layers.get_mut(id) {
+                transition.to.pixels = effect.render(device, time).iter().map(to_rgb).collect();
+            }
+        }
+    }
+
+    pub fn total_leds(&self) -> usize {
+        self.total_leds
+    }
+
+    // Fade the layer into a new state, a new layer fades in from transparent
+    pub fn set(&mut self, id: LayerId, layer: Layer, transition: TransitionProfile) {
+        let now = Instant::now();
+        let from = match self.layers.get(&id) {
+            // Already heading there, don't restart (or speed up) the fade
+            Some(transition) if !transition.removing && transition.to == layer => return,
+            Some(transition) => transition.current(now, *COLOR_SPACE.read().unwrap()),
+            None => layer.transparent(),
+        };
+        self.layers.insert(
+            id,
+            Transition {
+                from,
+                to: layer,
+                start: now,
+                duration: Duration::from_millis(transition.duration_ms as u64),
+                easing: transition.easing,
+                removing: false,
+            },
+        );
+    }
+
+    // Fade the layer out and drop it
+    pub fn remove(&mut self, id: &LayerId, transition: TransitionProfile) {
+        let now = Instant::now();
+        if let Some(layer) = self.layers.get_mut(id) {
+            if layer.removing {
+                return;
+            }
+            let current = layer.current(now, *COLOR_SPACE.read().unwrap());
+            layer.to = current.transparent();
+            layer.from = current;
+            layer.start = now;
+            layer.duration = Duration::from_millis(transition.duration_ms as u64);
+            layer.easing = transition.easing;
+            layer.removing = true;
+        }
+    }
+
+    pub fn remove_where(
+        &mut self,
+        matches: impl Fn(&LayerId) -> bool,
+        transition: TransitionProfile,
+    ) {
+        let ids: Vec<LayerId> = self
+            .layers
+            .keys()
+            .filter(|id| matches(id))
+            .cloned()
+            .collect();
+        for id in ids {
+            self.remove(&id, transition);
+        }
+    }
+
+    // Whether any layer is still fading
+    pub fn is_animating(&self) -> bool {
+        let now = Instant::now();
+        self.layers
+            .values()
+            .any(|transition| transition.progress(now) < 1.0)
+    }
+
+    // The frame to show right now, in full precision
+    pub fn flatten(&mut self) -> WideFrame {
+        let now = Instant::now();
+        let space = *COLOR_SPACE.read().unwrap();
+        self.layers
+            .retain(|_, transition| !transition.removing || transition.progress(now) < 1.0);
+        let layers = &self.layers;
+        self.effects.retain(|id, _| layers.contains_key(id));
+
+        let mut layers: Vec<Layer> = self
+            .layers
+            .values()
+            .map(|transition| transition.current(now, space))
+            .collect();
+        layers.sort_by_key(|layer| layer.z);
+
+        let mut out: Vec<Rgb> = vec![[0.0; 3]; self.total_leds];
+        for layer in layers.iter().filter(|layer| layer.opacity > 0.0) {
+            for (index, (pixel, alpha)) in layer.pixels.iter().zip(layer.mask.iter()).enumerate() {
+                let alpha = alpha * layer.opacity;
+                let Some(below) = out.get_mut(index) else {
+                    break;
+                };
+                if alpha <= 0.0 {
+                    continue;
+                }
+                let blended = layer.blend.blend(space, *below, *pixel);
+                *below = space.mix(*below, blended, alpha);
+            }
+        }
+
+        out.into_iter().map(WideColor::from_rgb).collect()
+    }
+}
+
+// Darken everything on the keyboard, 1 = full brightness
+pub fn set_ambient_dim(brightness: f64, transition: TransitionProfile) {
+    let mut layers = KEYBOARD_LAYERS.write().unwrap();
+    let layer = Layer::dim(&LayerId::AmbientDim, layers.total_leds(), brightness);
+    layers.set(LayerId::AmbientDim, layer, transition);
+}
@@ -0,0 +1,1145 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use openrgb2::Color;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer};
+use serde_json::value::RawValue;
+
+use crate::{
+    animations::AnimationsConfig, colorspace::ColorSpace, compositor::BlendMode, consts::*,
+    easing::Easing, effects::EffectConfig, geometry::PlacementConfig, selector::KeySelector,
+    utils::parse_hex,
+};
+
+// What a device shows
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceRole {
+    // Notifications, progress and the layout, there is one
+    Keyboard,
+    // An effect, dimmed with the keyboard
+    #[default]
+    #[serde(alias = "ambient-strip")]
+    AmbientStrip,
+    // A scaled copy of the keyboard
+    Mirror,
+    // Kept black
+    Off,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub zone: String,
+    // Set by the section for the keyboard and backlight sections
+    #[serde(default)]
+    pub role: DeviceRole,
+    #[serde(default)]
+    pub top_bar: TopBarConfig,
+    // Animation shown on the device: under the notifications on the keyboard, instead of the wave on the backlight
+    #[serde(default)]
+    pub effect: Option<EffectConfig>,
+    // Physical layout file (native or keyboard-layout-editor json), relative to the config file
+    #[serde(default)]
+    pub geometry: Option<PathBuf>,
+    // Where the device is on the desk, for effects that span several devices
+    #[serde(default)]
+    pub placement: PlacementConfig,
+    // Name of the OpenRGB server (under openrgb.servers) the device is on, the main one if not set
+    #[serde(default)]
+    pub server: Option<String>,
+}
+
+// Workarounds for quirks of some keyboards (skip the esc key, etc), how many leds of the top row the
+// progress and notification bar leaves out at the start and at the end
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TopBarConfig {
+    pub offset_start: usize,
+    pub offset_end: usize,
+}
+
+impl Default for TopBarConfig {
+    fn default() -> Self {
+        TopBarConfig {
+            offset_start: KEYBOARD_COL_OFFSET_START,
+            offset_end: KEYBOARD_COL_OFFSET_END,
+        }
+    }
+}
+
+// The main OpenRGB server and any number of others whose devices are used too, read on every (re)connect
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenRgbConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_name: String,
+    pub servers: BTreeMap<String, ServerConfig>,
+}
+
+impl Default for OpenRgbConfig {
+    fn default() -> Self {
+        OpenRgbConfig {
+            host: OPENRGB_DEFAULT_HOST.to_owned(),
+            port: OPENRGB_DEFAULT_PORT,
+            client_name: OPENRGB_CLIENT_NAME.to_owned(),
+            servers: BTreeMap::new(),
+        }
+    }
+}
+
+impl OpenRgbConfig {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub host: String,
+    #[serde(default = "default_openrgb_port")]
+    pub port: u16,
+}
+
+impl ServerConfig {
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+}
+
+fn default_openrgb_port() -> u16 {
+    OPENRGB_DEFAULT_PORT
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct HomeAssistantConfig {
+    pub url: String,
+    #[serde(default)]
+    pub token: String,
+
+    #[serde(rename = "sensor_id")]
+    pub light_sensor_id: String,
+    #[serde(default = "default_lux_threshold")]
+    pub lux_threshold: f64,
+    #[serde(default = "default_poll_interval_seconds")]
+    pub poll_interval_seconds: u64,
+    #[serde(default = "default_dim_brightness_mult")]
+    pub dim_brightness_mult: f64,
+}
+
+fn default_lux_threshold() -> f64 {
+    70.0
+}
+
+fn default_poll_interval_seconds() -> u64 {
+    30
+}
+
+fn default_dim_brightness_mult() -> f64 {
+    0.3
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationSettings {
+    #[serde(deserialize_with = "deserialize_color")]
+    pub color: Color,
+    pub important: bool,
+    pub flash_on_notify: bool,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub flash_on_auto_close: Color,
+}
+
+// Every color the daemon paints with, anything not set falls back to the compiled-in value
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    #[serde(deserialize_with = "deserialize_color")]
+    pub main: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub top_row: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub function: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub function2: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub num_pad: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub locked_screen: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub idle_locked_screen: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub idle_base: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub idle_nums: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub idle_space: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub gray: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub backlight_wave1: Color,
+    #[serde(deserialize_with = "deserialize_color")]
+    pub backlight_wave2: Color,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            main: MAIN_COLOR,
+            top_row: TOP_ROW_COLOR,
+            function: FUNCTION_COLOR,
+            function2: FUNCTION_COLOR2,
+            num_pad: NUM_PAD_COLOR,
+            locked_screen: LOCKED_SCREEN_COLOR,
+            idle_locked_screen: IDLE_COLOR_LOCKED_SCREEN,
+            idle_base: IDLE_COLOR_BASE,
+            idle_nums: IDLE_COLOR_NUMS,
+            idle_space: IDLE_COLOR_SPACE,
+            gray: GRAY,
+            backlight_wave1: BACKLIGHT_WAVE1_COLOR,
+            backlight_wave2: BACKLIGHT_WAVE2_COLOR,
+        }
+    }
+}
+
+impl Theme {
+    // Look a color up by its name in the theme section
+    pub fn get(&self, name: &str) -> Option<Color> {
+        match name {
+            "main" => Some(self.main),
+            "top_row" => Some(self.top_row),
+            "function" => Some(self.function),
+            "function2" => Some(self.function2),
+            "num_pad" => Some(self.num_pad),
+            "locked_screen" => Some(self.locked_screen),
+            "idle_locked_screen" => Some(self.idle_locked_screen),
+            "idle_base" => Some(self.idle_base),
+            "idle_nums" => Some(self.idle_nums),
+            "idle_space" => Some(self.idle_space),
+            "gray" => Some(self.gray),
+            "backlight_wave1" => Some(self.backlight_wave1),
+            "backlight_wave2" => Some(self.backlight_wave2),
+            _ => None,
+        }
+    }
+}
+
+// A color in the config, either written out or referring to a color of the active theme
+#[derive(Clone)]
+pub enum ColorRef {
+    Theme(String),
+    Literal(Color),
+}
+
+impl ColorRef {
+    pub fn resolve(&self, theme: &Theme) -> Color {
+        match self {
+            ColorRef::Theme(name) => theme.get(name).unwrap_or(BLACK),
+            ColorRef::Literal(color) => *color,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ColorRef {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        // Theme names win over css color names ("gray")
+        if Theme::default().get(&value).is_some() {
+            return Ok(ColorRef::Theme(value));
+        }
+        parse_hex(&value).map(ColorRef::Literal).map_err(|_| {
+            serde::de::Error::custom(format!(
+                "'{value}' is neither a color nor a theme color name"
+            ))
+        })
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct KeyGroup {
+    pub keys: KeySelector,
+    pub color: ColorRef,
+    // Warn if a pattern of the group selects more leds than this
+    #[serde(default)]
+    pub max_leds: Option<usize>,
+}
+
+// What color the leds that aren't in any key group get, rows and columns are counted from the top left
+#[derive(Deserialize, Clone)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum FallbackRule {
+    Color {
+        color: ColorRef,
+    },
+    Rows {
+        rows: Vec<usize>,
+        color: ColorRef,
+        default: ColorRef,
+    },
+    Columns {
+        columns: Vec<usize>,
+        color: ColorRef,
+        default: ColorRef,
+    },
+    Index {
+        max_index: usize,
+        color: ColorRef,
+        default: ColorRef,
+    },
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SubstrateConfig {
+    #[serde(default)]
+    pub keys: Vec<KeyGroup>,
+    pub fallback: FallbackRule,
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LayoutConfig {
+    pub active: SubstrateConfig,
+    pub idle: SubstrateConfig,
+    // Keys that light up in the color of the current keyboard language
+    pub language_markers: KeySelector,
+    // Static layers painted over the keyboard
+    pub layers: Vec<LayerConfig>,
+}
+
+// A layer painted with one color or an effect, without z it is painted over the built-in ones
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LayerConfig {
+    pub name: String,
+    pub keys: KeySelector,
+    #[serde(default)]
+    pub color: Option<ColorRef>,
+    #[serde(default)]
+    pub effect: Option<EffectConfig>,
+    #[serde(default)]
+    pub z: Option<i32>,
+    #[serde(default = "default_layer_opacity")]
+    pub opacity: f64,
+    #[serde(default)]
+    pub blend: BlendMode,
+}
+
+fn default_layer_opacity() -> f64 {
+    1.0
+}
+
+impl Default for LayoutConfig {
+    fn default() -> Self {
+        LayoutConfig {
+            active: default_active_substrate(),
+            idle: default_idle_substrate(),
+            language_markers: builtin_selector(&[
+                "Key: Caps Lock",
+                "Key: Print Screen",
+                "Key: Scroll Lock",
+                "Key: Pause/Break",
+            ]),
+            layers: Vec::new(),
+        }
+    }
+}
+
+fn builtin_selector(keys: &[&str]) -> KeySelector {
+    KeySelector::new(keys).expect("Built-in key patterns are valid")
+}
+
+fn key_group(keys: &[&str], color: ColorRef) -> KeyGroup {
+    KeyGroup {
+        keys: builtin_selector(keys),
+        color,
+        max_leds: None,
+    }
+}
+
+fn theme_color(name: &str) -> ColorRef {
+    ColorRef::Theme(name.to_owned())
+}
+
+// Colored according to my preferences (Razer Ornata, the top row are the first 15 leds)
+fn default_active_substrate() -> SubstrateConfig {
+    SubstrateConfig {
+        keys: vec![
+            key_group(
+                &["Key: Number Pad *", "Key: Num Lock"],
+                theme_color("num_pad"),
+            ),
+            key_group(
+                &[
+                    "Key: Insert",
+                    "Key: Delete",
+                    "Key: Page *",
+                    "Key: * Arrow",
+                    "Key: End",
+                    "Key: Home",
+                ],
+                theme_color("function"),
+            ),
+            key_group(
+                &["Key: Print Screen", "Key: Scroll Lock", "Key: Pause/Break"],
+                theme_color("function2"),
+            ),
+        ],
+        fallback: FallbackRule::Rows {
+            rows: vec![0],
+            color: theme_color("top_row"),
+            default: theme_color("main"),
+        },
+    }
+}
+
+fn default_idle_substrate() -> SubstrateConfig {
+    SubstrateConfig {
+        keys: vec![
+            key_group(
+                &[
+                    "Key: Number Pad *",
+                    "Key: Num Lock",
+                    "Key: Insert",
+                    "Key: Delete",
+                    "Key: Page *",
+                    "Key: * Arrow",
+                    "Key: End",
+                    "Key: Home",
+                    "Key: Print Screen",
+                    "Key: Scroll Lock",
+                    "Key: Pause/Break",
+                ],
+                ColorRef::Literal(BLACK),
+            ),
+            key_group(
+                &[
+                    "Key: `",
+                    "Key: 1",
+                    "Key: 2",
+                    "Key: 3",
+                    "Key: 4",
+                    "Key: 5",
+                    "Key: 6",
+                    "Key: 7",
+                    "Key: 8",
+                    "Key: 9",
+                    "Key: 0",
+                    "Key: -",
+                    "Key: =",
+                    "Key: Backspace",
+                ],
+                theme_color("idle_nums"),
+            ),
+            key_group(&["Key: Space"], theme_color("idle_space")),
+        ],
+        fallback: FallbackRule::Rows {
+            rows: vec![0],
+            color: ColorRef::Literal(BLACK),
+            default: theme_color("idle_base"),
+        },
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    pub active: Option<String>,
+    pub themes: BTreeMap<String, Theme>,
+}
+
+// After timeout_ms without input the keyboard shows the layout of the stage scaled by brightness
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct IdleStage {
+    pub timeout_ms: u32,
+    #[serde(default = "default_idle_brightness")]
+    pub brightness: f64,
+    // The idle layout is used when the stage doesn't have its own
+    #[serde(default)]
+    pub layout: Option<SubstrateConfig>,
+}
+
+fn default_idle_brightness() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdleConfig {
+    pub stages: Vec<IdleStage>,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        IdleConfig {
+            stages: vec![IdleStage {
+                timeout_ms: IDLE_TIMEOUT_MS,
+                brightness: 1.0,
+                layout: None,
+            }],
+        }
+    }
+}
+
+impl IdleConfig {
+    // Stages are counted from 1, 0 means the user is active
+    pub fn stage(&self, stage: usize) -> Option<&IdleStage> {
+        match stage {
+            0 => None,
+            _ => self.stages.get(stage - 1).or(self.stages.last()),
+        }
+    }
+}
+
+// How long a change takes and how it's paced
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TransitionProfile {
+    pub duration_ms: u32,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+impl TransitionProfile {
+    pub const fn new(duration_ms: u32, easing: Easing) -> TransitionProfile {
+        TransitionProfile {
+            duration_ms,
+            easing,
+        }
+    }
+
+    pub const fn linear(duration_ms: u32) -> TransitionProfile {
+        TransitionProfile::new(duration_ms, Easing::Linear)
+    }
+}
+
+// Timing of every kind of change on the keyboard
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransitionsConfig {
+    pub progress: TransitionProfile,
+    pub flash_in: TransitionProfile,
+    pub flash_out: TransitionProfile,
+    pub notification: TransitionProfile,
+    pub language: TransitionProfile,
+    pub lock: TransitionProfile,
+    pub idle: TransitionProfile,
+    pub profile_switch: TransitionProfile,
+    pub ambient: TransitionProfile,
+}
+
+impl Default for TransitionsConfig {
+    fn default() -> Self {
+        TransitionsConfig {
+            progress: TransitionProfile::linear(110),
+            flash_in: TransitionProfile::linear(300),
+            flash_out: TransitionProfile::linear(300),
+            notification: TransitionProfile::linear(200),
+            language: TransitionProfile::linear(100),
+            lock: TransitionProfile::linear(1500),
+            idle: TransitionProfile::linear(1500),
+            profile_switch: TransitionProfile::linear(1000),
+            ambient: TransitionProfile::linear(900),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderingConfig {
+    // Space that fades, blending and dimming interpolate in
+    pub color_space: ColorSpace,
+    // Temporal dithering of the 8 bit output
+    pub dithering: bool,
+}
+
+impl Default for RenderingConfig {
+    fn default() -> Self {
+        RenderingConfig {
+            color_space: ColorSpace::default(),
+            dithering: true,
+        }
+    }
+}
+
+// A bundle of settings that can be switched at runtime
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    // Name of a theme from the theme section, overrides theme.active
+    #[serde(default)]
+    pub theme: Option<String>,
+    // Replaces the layout section
+    #[serde(default)]
+    pub layout: Option<LayoutConfig>,
+    // Only notifications of these apps are shown, all of them if not set
+    #[serde(default)]
+    pub notification_apps: Option<Vec<String>>,
+    #[serde(default = "default_backlight_wave")]
+    pub backlight_wave: bool,
+}
+
+fn default_backlight_wave() -> bool {
+    true
+}
+
+impl Profile {
+    pub fn shows_notifications_of(&self, application: &str) -> bool {
+        self.notification_apps
+            .as_ref()
+            .is_none_or(|apps| apps.iter().any(|app| app == application))
+    }
+}
+
+pub struct Config {
+    pub openrgb: OpenRgbConfig,
+    // The keyboard and backlight sections come first, then the devices list
+    pub devices: Vec<DeviceConfig>,
+    pub home_assistant: Option<HomeAssistantConfig>,
+    pub notification_map: BTreeMap<String, NotificationSettings>,
+    pub progress_map: BTreeMap<String, Color>,
+    pub language_color_map: BTreeMap<String, Color>,
+    pub theme: ThemeConfig,
+    pub layout: LayoutConfig,
+    pub idle: IdleConfig,
+    pub transitions: TransitionsConfig,
+    pub rendering: RenderingConfig,
+    pub animations: AnimationsConfig,
+    pub profiles: BTreeMap<String, Profile>,
+    // Profile used when none was switched to yet
+    pub default_profile: Option<String>,
+}
+
+impl Config {
+    // The selected theme, or the compiled-in colors if none is selected
+    pub fn active_theme(&self, profile: Option<&Profile>) -> Theme {
+        profile
+            .and_then(|profile| profile.theme.as_ref())
+            .or(self.theme.active.as_ref())
+            .and_then(|name| self.theme.themes.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn active_layout<'a>(&'a self, profile: Option<&'a Profile>) -> &'a LayoutConfig {
+        profile
+            .and_then(|profile| profile.layout.as_ref())
+            .unwrap_or(&self.layout)
+    }
+
+    // There is always exactly one, the config doesn't load otherwise
+    pub fn keyboard(&self) -> &DeviceConfig {
+        self.devices
+            .iter()
+            .find(|device| device.role == DeviceRole::Keyboard)
+            .expect("Config without a keyboard")
+    }
+
+    // The entry of a device, it can be gone after a reload
+    pub fn device(&self, server: Option<&str>, name: &str, zone: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|device| {
+            device.server.as_deref() == server && device.name == name && device.zone == zone
+        })
+    }
+}
+
+// A single thing wrong with the config file, pointing at where it was found
+pub struct ConfigProblem {
+    pub path: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = match (self.line, self.column) {
+            (Some(line), Some(column)) => format!("line {line}, column {column}"),
+            _ => String::new(),
+        };
+        match (self.path.is_empty(), location.is_empty()) {
+            (true, true) => write!(f, "{}", self.message),
+            (true, false) => write!(f, "{location}: {}", self.message),
+            (false, true) => write!(f, "{}: {}", self.path, self.message),
+            (false, false) => write!(f, "{} ({location}): {}", self.path, self.message),
+        }
+    }
+}
+
+pub struct ConfigError {
+    pub file: PathBuf,
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid config {} ({} problem{}):",
+            self.file.display(),
+            self.problems.len(),
+            if self.problems.len() == 1 { "" } else { "s" }
+        )?;
+        for problem in &self.problems {
+            write!(f, "\n  - {problem}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+impl Error for ConfigError {}
+
+pub fn deserialize_color<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+    let col = String::deserialize(deserializer)?;
+    parse_hex(&col).map_err(|_| serde::de::Error::custom(format!("invalid color '{col}'")))
+}
+
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct ColorValue(#[serde(deserialize_with = "deserialize_color")] Color);
+
+const KNOWN_SECTIONS: [&str; 16] = [
+    "openrgb",
+    "keyboard",
+    "backlight",
+    "devices",
+    "home_assistant",
+    "notification_map",
+    "progress_map",
+    "language_color_map",
+    "theme",
+    "layout",
+    "idle",
+    "transitions",
+    "rendering",
+    "animations",
+    "profiles",
+    "default_profile",
+];
+
+// Places the config is looked up in when it's not given on the command line, in order
+pub fn config_search_paths() -> Vec<PathBuf> {
+    let user_config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")));
+
+    user_config_dir
+        .map(|dir| dir.join(CONFIG_DIR_NAME).join(CONFIG_FILE_NAME))
+        .into_iter()
+        .chain([Path::new(SYSTEM_CONFIG_DIR).join(CONFIG_FILE_NAME)])
+        .collect()
+}
+
+pub fn find_config_file(explicit: Option<PathBuf>) -> Result<PathBuf, String> {
+    if let Some(path) = explicit {
+        return Ok(path);
+    }
+    let candidates = config_search_paths();
+    candidates
+        .iter()
+        .find(|path| path.is_file())
+        .cloned()
+        .ok_or_else(|| {
+            format!(
+                "No config file found, looked in: {}. Pass one with --config",
+                candidates
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+pub fn current_config() -> Arc<Config> {
+    CONFIG
+        .read()
+        .unwrap()
+        .clone()
+        .expect("Config is accessed before it was loaded")
+}
+
+pub fn load_config(path: &Path) -> Result<Config, ConfigError> {
+    let source = fs::read_to_string(path).map_err(|e| ConfigError {
+        file: path.to_owned(),
+        problems: vec![ConfigProblem {
+            path: String::new(),
+            line: None,
+            column: None,
+            message: format!("could not read the file: {e}"),
+        }],
+    })?;
+    parse_config(&source).map_err(|problems| ConfigError {
+        file: path.to_owned(),
+        problems,
+    })
+}
+
+// Parse the whole file, collecting every problem instead of stopping at the first one
+pub fn parse_config(source: &str) -> Result<Config, Vec<ConfigProblem>> {
+    let mut loader = Loader {
+        source,
+        problems: Vec::new(),
+    };
+
+    let root: BTreeMap<String, &RawValue> = match serde_json::from_str(source) {
+        Ok(root) => root,
+        Err(e) => {
+            loader.report_json_error(String::new(), e, None);
+            return Err(loader.problems);
+        }
+    };
+
+    for (key, raw) in &root {
+        if !KNOWN_SECTIONS.contains(&key.as_str()) {
+            loader.report(key.to_owned(), Some(*raw), "unknown section");
+        }
+    }
+
+    let openrgb = loader
+        .optional::<OpenRgbConfig>(&root, "openrgb")
+        .unwrap_or_default();
+    let keyboard = loader.optional::<DeviceConfig>(&root, "keyboard");
+    let backlight = loader.optional::<DeviceConfig>(&root, "backlight");
+    let devices_raw = root.get("devices").copied();
+    let devices: Vec<DeviceConfig> = keyboard
+        .map(|device| DeviceConfig {
+            role: DeviceRole::Keyboard,
+            ..device
+        })
+        .into_iter()
+        .chain(backlight.map(|device| DeviceConfig {
+            role: DeviceRole::AmbientStrip,
+            ..device
+        }))
+        .chain(
+            loader
+                .optional::<Vec<DeviceConfig>>(&root, "devices")
+                .unwrap_or_default(),
+        )
+        .collect();
+    match devices
+        .iter()
+        .filter(|device| device.role == DeviceRole::Keyboard)
+        .count()
+    {
+        0 => loader.report(
+            "keyboard".to_owned(),
+            None,
+            "no keyboard, add a keyboard section or a device with the keyboard role",
+        ),
+        1 => {}
+        _ => loader.report(
+            "devices".to_owned(),
+            devices_raw,
+            "only one device can be the keyboard",
+        ),
+    }
+    for (i, device) in devices.iter().enumerate() {
+        if let Some(server) = &device.server {
+            if !openrgb.servers.contains_key(server) {
+                loader.report(
+                    "devices".to_owned(),
+                    devices_raw,
+                    format!(
+                        "'{}' is on server '{server}', which is not in openrgb.servers",
+                        device.name
+                    ),
+                );
+            }
+        }
+        if devices[..i].iter().any(|other| {
+            other.name == device.name && other.zone == device.zone && other.server == device.server
+        }) {
+            loader.report(
+                "devices".to_owned(),
+                devices_raw,
+                format!(
+                    "zone '{}' of '{}' is listed twice",
+                    device.zone, device.name
+                ),
+            );
+        }
+    }
+    let home_assistant = loader
+        .optional::<Option<HomeAssistantConfig>>(&root, "home_assistant")
+        .flatten();
+    let notification_map = loader.map::<NotificationSettings>(&root, "notification_map");
+    let progress_map = loader
+        .map::<ColorValue>(&root, "progress_map")
+        .into_iter()
+        .map(|(key, color)| (key, color.0))
+        .collect();
+    let language_color_map = loader
+        .map::<ColorValue>(&root, "language_color_map")
+        .into_iter()
+        .map(|(key, color)| (key, color.0))
+        .collect();
+
+    let theme = loader
+        .optional::<ThemeConfig>(&root, "theme")
+        .unwrap_or_default();
+    if let Some(active) = &theme.active {
+        if !theme.themes.contains_key(active) {
+            loader.report(
+                "theme.active".to_owned(),
+                root.get("theme").copied(),
+                format!(
+                    "theme '{active}' is not defined, available: {}",
+                    theme.themes.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            );
+        }
+    }
+
+    let layout = loader
+        .optional::<LayoutConfig>(&root, "layout")
+        .unwrap_or_default();
+    check_layers(&mut loader, "layout", &layout, root.get("layout").copied());
+
+    let idle = loader
+        .optional::<IdleConfig>(&root, "idle")
+        .unwrap_or_default();
+    let idle_raw = root.get("idle").copied();
+    if idle.stages.is_empty() {
+        loader.report(
+            "idle.stages".to_owned(),
+            idle_raw,
+            "at least one stage is needed",
+        );
+    }
+    for (i, stage) in idle.stages.iter().enumerate() {
+        if !(0.0..=1.0).contains(&stage.brightness) {
+            loader.report(
+                format!("idle.stages[{i}].brightness"),
+                idle_raw,
+                format!("must be between 0 and 1, got {}", stage.brightness),
+            );
+        }
+        if i > 0 && stage.timeout_ms <= idle.stages[i - 1].timeout_ms {
+            loader.report(
+                format!("idle.stages[{i}].timeout_ms"),
+                idle_raw,
+                "stages must have increasing timeouts",
+            );
+        }
+    }
+
+    let transitions = loader
+        .optional::<TransitionsConfig>(&root, "transitions")
+        .unwrap_or_default();
+
+    let rendering = loader
+        .optional::<RenderingConfig>(&root, "rendering")
+        .unwrap_or_default();
+
+    let animations = loader
+        .optional::<AnimationsConfig>(&root, "animations")
+        .unwrap_or_default();
+
+    let profiles = loader.map::<Profile>(&root, "profiles");
+    for (name, profile) in &profiles {
+        if let Some(layout) = &profile.layout {
+            let path = format!("profiles.{name}.layout");
+            check_layers(&mut loader, &path, layout, root.get("profiles").copied());
+        }
+        if let Some(theme_name) = &profile.theme {
+            if !theme.themes.contains_key(theme_name) {
+                loader.report(
+                    format!("profiles.{name}.theme"),
+                    root.get("profiles").copied(),
+                    format!("theme '{theme_name}' is not defined"),
+                );
+            }
+        }
+    }
+    let default_profile = loader.optional::<String>(&root, "default_profile");
+    if let Some(default_profile) = &default_profile {
+        if !profiles.contains_key(default_profile) {
+            loader.report(
+                "default_profile".to_owned(),
+                root.get("default_profile").copied(),
+                format!("profile '{default_profile}' is not defined"),
+            );
+        }
+    }
+
+    if let Some(ha) = &home_assistant {
+        if !(0.0..=1.0).contains(&ha.dim_brightness_mult) {
+            loader.report(
+                "home_assistant.dim_brightness_mult".to_owned(),
+                root.get("home_assistant").copied(),
+                format!("must be between 0 and 1, got {}", ha.dim_brightness_mult),
+            );
+        }
+    }
+
+    if !loader.problems.is_empty() {
+        return Err(loader.problems);
+    }
+    Ok(Config {
+        openrgb,
+        devices,
+        home_assistant,
+        notification_map,
+        progress_map,
+        language_color_map,
+        theme,
+        layout,
+        idle,
+        transitions,
+        rendering,
+        animations,
+        profiles,
+        default_profile,
+    })
+}
+
+fn check_layers(loader: &mut Loader, path: &str, layout: &LayoutConfig, raw: Option<&RawValue>) {
+    for (i, layer) in layout.layers.iter().enumerate() {
+        if !(0.0..=1.0).contains(&layer.opacity) {
+            loader.report(
+                format!("{path}.layers[{i}].opacity"),
+                raw,
+                format!("must be between 0 and 1, got {}", layer.opacity),
+            );
+        }
+        if layer.color.is_some() == layer.effect.is_some() {
+            loader.report(
+                format!("{path}.layers[{i}]"),
+                raw,
+                "needs either a color or an effect",
+            );
+        }
+        if layout.layers[..i]
+            .iter()
+            .any(|other| other.name == layer.name)
+        {
+            loader.report(
+                format!("{path}.layers[{i}].name"),
+                raw,
+                format!("layer '{}' is defined twice", layer.name),
+            );
+        }
+    }
+}
+
+struct Loader<'a> {
+    source: &'a str,
+    problems: Vec<ConfigProblem>,
+}
+
+impl<'a> Loader<'a> {
+    // Line and column (1-based) of a raw value inside the source file
+    fn position(&self, raw: &RawValue) -> (usize, usize) {
+        let offset = (raw.get().as_ptr() as usize).saturating_sub(self.source.as_ptr() as usize);
+        let before = &self.source[..offset.min(self.source.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        (line, column)
+    }
+
+    fn report(&mut self, path: String, raw: Option<&RawValue>, message: impl Into<String>) {
+        let position = raw.map(|raw| self.position(raw));
+        self.problems.push(ConfigProblem {
+            path,
+            line: position.map(|(line, _)| line),
+            column: position.map(|(_, column)| column),
+            message: message.into(),
+        });
+    }
+
+    fn report_json_error(&mut self, path: String, e: serde_json::Error, raw: Option<&RawValue>) {
+        // serde_json appends the position to the message, we print our own
+        let message = e.to_string();
+        let suffix = format!(" at line {} column {}", e.line(), e.column());
+        let message = message
+            .strip_suffix(suffix.as_str())
+            .unwrap_or(&message)
+            .to_owned();
+
+        let (base_line, base_column) = raw.map_or((1, 1), |raw| self.position(raw));
+        let (line, column) = match e.line() {
+            0 => (base_line, base_column),
+            1 => (base_line, base_column + e.column().saturating_sub(1)),
+            l => (base_line + l - 1, e.column()),
+        };
+        self.problems.push(ConfigProblem {
+            path,
+            line: Some(line),
+            column: Some(column),
+            message,
+        });
+    }
+
+    fn parse<T: DeserializeOwned>(&mut self, path: &str, raw: &'a RawValue) -> Option<T> {
+        let mut deserializer = serde_json::Deserializer::from_str(raw.get());
+        match serde_path_to_error::deserialize(&mut deserializer) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                let inner_path = e.path().to_string();
+                let full_path = match inner_path.as_str() {
+                    "." => path.to_owned(),
+                    _ => format!("{path}.{inner_path}"),
+                };
+                self.report_json_error(full_path, e.into_inner(), Some(raw));
+                None
+            }
+        }
+    }
+
+    fn optional<T: DeserializeOwned>(
+        &mut self,
+        root: &BTreeMap<String, &'a RawValue>,
+        name: &str,
+    ) -> Option<T> {
+        root.get(name)
+            .copied()
+            .and_then(|raw| self.parse(name, raw))
+    }
+
+    // Parse every entry of a map section separately so one bad entry doesn't hide the others
+    fn map<T: DeserializeOwned>(
+        &mut self,
+        root: &BTreeMap<String, &'a RawValue>,
+        name: &str,
+    ) -> BTreeMap<String, T> {
+        let Some(raw) = root.get(name).copied() else {
+            return BTreeMap::new();
+        };
+        let entries: BTreeMap<String, &'a RawValue> = match serde_json::from_str(raw.get()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.report_json_error(name.to_owned(), e, Some(raw));
+                return BTreeMap::new();
+            }
+        };
+        entries
+            .into_iter()
+            .filter_map(|(key, raw)| {
+                let value = self.parse(&format!("{name}.{key}"), raw)?;
+                Some((key, value))
+            })
+            .collect()
+    }
+}
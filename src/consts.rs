@@ -1,35 +1,59 @@
-use std::sync::{
-    atomic::{AtomicBool, AtomicU8},
-    Arc, Mutex, RwLock,
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU8, AtomicUsize},
+        Arc, RwLock,
+    },
+    time::Instant,
 };
 
 use atomic::Atomic;
 use color_hex::color_from_hex;
-use concurrent_queue::ConcurrentQueue;
-use css_color_parser::Color as CssColor;
 use once_cell::sync::Lazy;
 use openrgb2::Color;
 
-use crate::u8_to_col;
+use crate::{
+    colorspace::ColorSpace,
+    compositor::LayerStack,
+    config::{Config, Theme},
+    u8_to_col,
+    utils::{WideFrame, ZonedControllerInfo},
+};
 
+// Timeout of the idle stage used when the config doesn't define any
 pub const IDLE_TIMEOUT_MS: u32 = 60_000 * 3;
 
+// Default top bar offsets, see TopBarConfig
 // Workarounds quirks in some keyboards (skip esc key, etc) this offsets the starting position of the top bar
 pub const KEYBOARD_COL_OFFSET_START: usize = 1;
 // The same, but fo the end of the top bar
 pub const KEYBOARD_COL_OFFSET_END: usize = 4;
 
+// Where the config is looked up (under $XDG_CONFIG_HOME, then the system directory)
+pub const CONFIG_DIR_NAME: &str = "keyboard_vis";
+pub const CONFIG_FILE_NAME: &str = "config.json";
+pub const SYSTEM_CONFIG_DIR: &str = "/etc/keyboard_vis";
+// Remembers the active profile across restarts (under $XDG_STATE_HOME/keyboard_vis)
+pub const PROFILE_STATE_FILE_NAME: &str = "profile";
+// Modes and colors the devices had before the daemon took them over, removed once they are restored
+pub const SNAPSHOT_FILE_NAME: &str = "snapshot.json";
+
+// Distance between two keys in millimetres, the unit of physical layouts
+pub const KEY_PITCH_MM: f64 = 19.05;
+
+// Where the OpenRGB server is unless the config says otherwise, and what the daemon calls itself there
+pub const OPENRGB_DEFAULT_HOST: &str = "localhost";
+pub const OPENRGB_DEFAULT_PORT: u16 = 6742;
+pub const OPENRGB_CLIENT_NAME: &str = "Custom effects client";
+
+// How often the device list of OpenRGB is checked for devices being plugged in or out
+pub const DEVICE_POLL_INTERVAL_MS: u64 = 2000;
+// How long the servers of openrgb.servers get to answer, an unreachable host would hold everything up for minutes
+pub const SERVER_CONNECT_TIMEOUT_MS: u64 = 500;
+
 // How many ms per frame
 pub const FRAME_DURATION_MS: u32 = 75;
 
-// Define some constants (colors)
-pub const TRANSPARENT_BLACK: CssColor = CssColor {
-    r: 0,
-    g: 0,
-    b: 0,
-    a: 1.0,
-};
-
+// Define some constants (colors), these are the defaults of the theme
 pub const BLACK: Color = Color { r: 0, g: 0, b: 0 };
 pub const WHITE: Color = Color {
     r: 255,
@@ -66,28 +90,50 @@ pub const RED: Color = u8_to_col(color_from_hex!("#ff0000"));
 pub const GREEN: Color = u8_to_col(color_from_hex!("#00ff00"));
 pub const BLUE: Color = u8_to_col(color_from_hex!("#0000ff"));
 pub const PURPLE: Color = u8_to_col(color_from_hex!("#ff00ff"));
+pub const YELLOW: Color = u8_to_col(color_from_hex!("#ffff00"));
 
-pub static KEYBOARD_LAST_FRAME: Lazy<RwLock<Frame>> = Lazy::new(|| RwLock::new(Vec::new()));
 pub static KEYBOARD_BASE_FRAME: Lazy<RwLock<Frame>> = Lazy::new(|| RwLock::new(Vec::new()));
-pub static KEYBOARD_IDLE_FRAME: Lazy<RwLock<Frame>> = Lazy::new(|| RwLock::new(Vec::new()));
-pub static KEYBOARD_FRAME_Q: Lazy<ConcurrentQueue<Frame>> = Lazy::new(ConcurrentQueue::unbounded);
-
-pub static CURRENT_LANGUAGE_COLOR_MARKER_KEYS: Lazy<Vec<&str>> = Lazy::new(|| {
-    Vec::from([
-        "Key: Caps Lock",
-        "Key: Print Screen",
-        "Key: Scroll Lock",
-        "Key: Pause/Break",
-    ])
-});
+// One per idle stage, in order
+pub static KEYBOARD_IDLE_FRAMES: Lazy<RwLock<Vec<Frame>>> = Lazy::new(|| RwLock::new(Vec::new()));
+pub static KEYBOARD_LAYERS: Lazy<RwLock<LayerStack>> =
+    Lazy::new(|| RwLock::new(LayerStack::default()));
+
+// Indexes of the leds showing the keyboard language
+pub static LANGUAGE_MARKER_LEDS: Lazy<RwLock<Vec<usize>>> = Lazy::new(|| RwLock::new(Vec::new()));
 
 // Arc for screen lock state and flash color
 pub static SCREEN_LOCKED: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
 pub static CURRENT_LANGUAGE_COLOR_MODIFIER: Lazy<Arc<Atomic<Color>>> =
     Lazy::new(|| Arc::new(Atomic::new(BLACK)));
-pub static USER_IDLE: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(false)));
+// 0 while the user is active, otherwise the number of the idle stage that was reached
+pub static USER_IDLE_STAGE: Lazy<Arc<AtomicUsize>> = Lazy::new(|| Arc::new(AtomicUsize::new(0)));
 pub static ABOUT_TO_SHUTDOWN: Lazy<Arc<AtomicU8>> = Lazy::new(|| Arc::new(AtomicU8::new(0)));
+// When the shutdown animation started playing
+pub static SHUTDOWN_STARTED: Lazy<RwLock<Option<Instant>>> = Lazy::new(|| RwLock::new(None));
 pub static KEYBOARD_FLASH_COLOR: Lazy<Arc<Atomic<Color>>> =
     Lazy::new(|| Arc::new(Atomic::new(BLACK)));
 
 pub static AMBIENT_BRIGHTNESS: Lazy<Arc<Atomic<f64>>> = Lazy::new(|| Arc::new(Atomic::new(1.0)));
+
+// Currently loaded config, replaced on reload. The generation is bumped every time it changes
+pub static CONFIG: Lazy<RwLock<Option<Arc<Config>>>> = Lazy::new(|| RwLock::new(None));
+pub static CONFIG_GENERATION: Lazy<Arc<AtomicU32>> = Lazy::new(|| Arc::new(AtomicU32::new(0)));
+pub static THEME: Lazy<RwLock<Theme>> = Lazy::new(|| RwLock::new(Theme::default()));
+pub static COLOR_SPACE: Lazy<RwLock<ColorSpace>> = Lazy::new(|| RwLock::new(ColorSpace::default()));
+pub static DITHERING: Lazy<Arc<AtomicBool>> = Lazy::new(|| Arc::new(AtomicBool::new(true)));
+// Effects of all devices share the clock so they line up on the desk
+pub static RENDER_START: Lazy<Instant> = Lazy::new(Instant::now);
+// Keyboard of the latest session, the last one stays while it's unplugged.
+// The generation is bumped every time it changes
+pub static KEYBOARD: Lazy<RwLock<Option<Arc<ZonedControllerInfo>>>> =
+    Lazy::new(|| RwLock::new(None));
+pub static KEYBOARD_GENERATION: Lazy<Arc<AtomicU32>> = Lazy::new(|| Arc::new(AtomicU32::new(0)));
+// Where desk-wide effects start unless they say otherwise, led units
+pub static KEYBOARD_DESK_CENTER: Lazy<RwLock<(f64, f64)>> = Lazy::new(|| RwLock::new((0.0, 0.0)));
+// Last frame of the keyboard before dithering, for the mirrors
+pub static KEYBOARD_FRAME: Lazy<RwLock<WideFrame>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+// Profile switched to at runtime, None until the first switch
+pub static ACTIVE_PROFILE: Lazy<RwLock<Option<String>>> = Lazy::new(|| RwLock::new(None));
+pub static BACKLIGHT_WAVE_ENABLED: Lazy<Arc<AtomicBool>> =
+    Lazy::new(|| Arc::new(AtomicBool::new(true)));
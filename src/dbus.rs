@@ -6,9 +6,16 @@ use std::{
     vec,
 };
 
-use crate::{ZonedControllerInfo, consts::*, utils::{
-    ColorMap, Notification, NotificationSettings, ProgressMap, composite, flash_color, get_timestamp, parse_hex,
-}};
+use crate::{
+    apply_keyboard_layout,
+    config::{current_config, Config, NotificationSettings, TransitionProfile},
+    consts::*,
+    profile::active_profile,
+    utils::{
+        composite, current_keyboard, flash_color, get_timestamp, ColorMap, Notification,
+        ProgressMap,
+    },
+};
 use dbus::{
     arg::{prop_cast, PropMap},
     blocking::Connection,
@@ -17,7 +24,6 @@ use dbus::{
     Message,
 };
 use log::{info, warn};
-use serde_json::Value;
 
 fn get_full_match_rule<'a>(interface: &'a str, path: &'a str, member: &'a str) -> MatchRule<'a> {
     MatchRule::with_member(
@@ -26,42 +32,60 @@ fn get_full_match_rule<'a>(interface: &'a str, path: &'a str, member: &'a str) -
     )
 }
 
-pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -> Result<(), Box<dyn Error>> {
+type NotificationMap = HashMap<String, Arc<NotificationSettings>>;
+
+// Fill the maps from the config, keeping the progress of anything that is already running
+fn load_maps(
+    config: &Config,
+    notification_map: &RwLock<NotificationMap>,
+    progress_map: &ProgressMap,
+    language_color_map: &ColorMap,
+) {
+    let mut notification_map = notification_map.write().unwrap();
+    notification_map.clear();
+    for (key, settings) in &config.notification_map {
+        info!("Loaded {} from notification map", key);
+        notification_map.insert(key.to_owned(), Arc::new(settings.clone()));
+    }
+
+    // Apps that are no longer configured go back to the default color
+    for mut entry in progress_map.iter_mut() {
+        if !config.progress_map.contains_key(entry.key()) {
+            entry.0 = WHITE;
+        }
+    }
+    for (key, color) in &config.progress_map {
+        info!("Loaded {} from progress map", key);
+        progress_map.entry(key.to_owned()).or_insert((WHITE, 0.0)).0 = *color;
+    }
+
+    language_color_map.clear();
+    for (key, color) in &config.language_color_map {
+        info!("Loaded {} from language color map", key);
+        language_color_map.insert(key.to_owned(), *color);
+    }
+}
+
+pub fn process_dbus() -> Result<(), Box<dyn Error>> {
     // Connect to the D-Bus session bus (this is blocking, unfortunately).
     let conn = Connection::new_session()?;
 
     let pending_notification_q = Arc::new(RwLock::new(Vec::<Notification>::new()));
     let notification_q = Arc::new(RwLock::new(Vec::<Notification>::new()));
 
-    let mut notification_map = HashMap::new();
+    let notification_map = Arc::new(RwLock::new(NotificationMap::new()));
     let progress_map = Arc::new(ProgressMap::new());
     let language_color_map = Arc::new(ColorMap::new());
 
-    for (key, value) in config_j["notification_map"]
-    .as_object()
-    .expect("notification_map map is missing from the json")
-    .into_iter() {
-        info!("Loaded {} from notification map", key);
-        notification_map.insert(
-            key.to_owned(),
-            Arc::new(NotificationSettings {
-                color: parse_hex(value["color"].as_str().expect("color is missing from {key}")),
-                flash_on_auto_close: parse_hex(value["flash_on_auto_close"].as_str().expect("flash_on_auto_close is missing from {key}")),
-                flash_on_notify: value["flash_on_notify"].as_bool().expect("flash_on_notify is missing from {key}"),
-                important: value["important"].as_bool().expect("important is missing from {key}"),
-            }),
-        );
-    }
-    
-    for (key, value) in config_j["progress_map"].as_object().expect("progress_map is missing from the json").into_iter() {
-        info!("Loaded {} from progress map", key);
-        progress_map.insert(key.to_owned(), (parse_hex(value.as_str().expect("failed getting value for {key} in progress_map")), 0.0));
-    }
-
-    for (key, value) in config_j["language_color_map"].as_object().expect("language_color_map is missing from the json").into_iter() {
-        info!("Loaded {} from language color map", key);
-        language_color_map.insert(key.to_owned(), parse_hex(value.as_str().expect("failed getting value for {key} in language_color_map")));
-    }
+    // Read the generation first so a reload racing with this one isn't missed
+    let mut config_generation = CONFIG_GENERATION.load(Ordering::Relaxed);
+    let mut keyboard_generation = KEYBOARD_GENERATION.load(Ordering::Relaxed);
+    load_maps(
+        &current_config(),
+        &notification_map,
+        &progress_map,
+        &language_color_map,
+    );
 
     let notification_delivery_timeout = 2000;
 
@@ -125,16 +149,15 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
     conn.start_receive(
         matchrule_progress,
         Box::new({
-
             let notification_q = notification_q.clone();
             let progress_map = progress_map.clone();
-            let keyboard_info_arc = keyboard_info.clone();
 
             move |message: Message, _| {
                 let (source, props): (&str, PropMap) = message.read2().unwrap();
-                
+
                 let progress: f64 = *prop_cast(&props, "progress").unwrap_or(&0.0);
-                let progress_visible: bool = *prop_cast(&props, "progress-visible").unwrap_or(&true);
+                let progress_visible: bool =
+                    *prop_cast(&props, "progress-visible").unwrap_or(&true);
                 let count: i32 = *prop_cast(&props, "count").unwrap_or(&0);
 
                 let progress_delta;
@@ -163,44 +186,57 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                     } else {
                         PURPLE // invisible notification without visible progress (spectacle call, download finished)
                     };
-                    flash_color(&keyboard_info_arc, color, 350, &progress_map, &notification_q);
+                    flash_color(
+                        &current_keyboard(),
+                        color,
+                        350,
+                        &progress_map,
+                        &notification_q,
+                    );
                 } else if progress_delta > 0.0 {
                     // recomposite if progress changed to not cause stalled animations
-                    composite(&keyboard_info_arc, &progress_map, &notification_q, None);
+                    composite(
+                        &current_keyboard(),
+                        &progress_map,
+                        &notification_q,
+                        current_config().transitions.progress,
+                    );
                 }
                 true
             }
-        })
+        }),
     );
 
     conn.start_receive(
         matchrule_language,
         Box::new({
-            
             // Copy all the necessary stuff to move into closure
+            let language_color_map = language_color_map.clone();
             let notifications = notification_q.clone();
             let progress_map = progress_map.clone();
-            let keyboard_info_arc = keyboard_info.clone();
 
             move |message: Message, _| {
                 let lang: &str = message.read1().unwrap();
                 let color = language_color_map.entry(lang.to_string()).or_default();
                 CURRENT_LANGUAGE_COLOR_MODIFIER.store(color.to_owned(), Ordering::Relaxed);
-                composite(&keyboard_info_arc, &progress_map, &notifications, Some(100));
+                composite(
+                    &current_keyboard(),
+                    &progress_map,
+                    &notifications,
+                    current_config().transitions.language,
+                );
                 true
             }
-        })
+        }),
     );
 
     conn.start_receive(
         matchrule_screen,
         Box::new({
-            
             // Copy all the necessary stuff to move into closure
             let screen_locked = SCREEN_LOCKED.clone();
             let notifications = notification_q.clone();
             let progress_map = progress_map.clone();
-            let keyboard_info_arc = keyboard_info.clone();
 
             move |message: Message, _| {
                 let locked: bool = message.read1().unwrap();
@@ -208,18 +244,23 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                 // Store screen locked state
                 screen_locked.store(locked, Ordering::Relaxed);
                 // Animate!
-                composite(&keyboard_info_arc, &progress_map, &notifications, Some(1500));
+                composite(
+                    &current_keyboard(),
+                    &progress_map,
+                    &notifications,
+                    current_config().transitions.lock,
+                );
                 true
             }
-        })
+        }),
     );
-    
+
     conn.start_receive(
         matchrule_notification_opened,
         Box::new({
-            
-            // Clone Arc for the notification queue
+            // Clone Arcs for the notification queue and map
             let pending_notification = pending_notification_q.clone();
+            let notification_map = notification_map.clone();
 
             move |message: Message, _| {
                 let (application, _, _, summary): (String, u32, String, String) =
@@ -228,7 +269,15 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                 info!("Notification sent from {application} ({sender}) | {summary}");
                 let mut pending_notif_q = pending_notification.write().unwrap();
 
-                match notification_map.get(application.as_str()) {
+                let config = current_config();
+                let shown = active_profile(&config)
+                    .is_none_or(|profile| profile.shows_notifications_of(&application));
+                if !shown {
+                    info!("Notifications of {application} are hidden in this profile, ignoring");
+                    return true;
+                }
+
+                match notification_map.read().unwrap().get(application.as_str()) {
                     Some(arc_settings) => {
                         pending_notif_q.push(Notification {
                             id: 0,
@@ -241,13 +290,13 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                 };
                 true
             }
-        })
+        }),
     );
 
     conn.start_receive(
         matchrule_notification_closed,
         Box::new({
-            
+
             let find_in_notif_q = |id: u32, notif_q: &Vec<Notification>| -> Option<usize> {
                 notif_q.iter().position(|notif| notif.id == id)
             };
@@ -255,7 +304,6 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
             let pending_notification_q = pending_notification_q.clone();
             let notification_q = notification_q.clone();
             let progress_map = progress_map.clone();
-            let keyboard_info_arc = keyboard_info.clone();
 
             move |message: Message, _| {
                 let (id, reason): (u32, u32) = message.read2().unwrap();
@@ -263,7 +311,7 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                 let mut pending_notif_q = pending_notification_q.write().unwrap();
 
                 let ind: Option<usize> = find_in_notif_q(id, &pending_notif_q);
-                
+
                 if let Some(ind) = ind {
                     let notif = pending_notif_q.remove(ind);
 
@@ -279,7 +327,7 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                     let settings = &notif.settings;
 
                     if settings.flash_on_auto_close != BLACK {
-                        flash_color(&keyboard_info_arc,
+                        flash_color(&current_keyboard(),
                             settings.flash_on_auto_close,
                             500,
                             &progress_map,
@@ -290,7 +338,7 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                     if settings.important {
                         notification_q.write().unwrap().push(notif);
                         info!("Moved pending notification {id} to display queue");
-                        composite(&keyboard_info_arc, &progress_map, &notification_q, Some(200));
+                        composite(&current_keyboard(), &progress_map, &notification_q, current_config().transitions.notification);
                     }
 
                     return true;
@@ -301,7 +349,7 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                 if let Some(ind_full) = ind_full {
                     info!(" -=-=- Hidden notification closed id: {id} | reason: {reason}");
                     notification_q.write().unwrap().remove(ind_full);
-                    composite(&keyboard_info_arc, &progress_map, &notification_q, Some(200));
+                    composite(&current_keyboard(), &progress_map, &notification_q, current_config().transitions.notification);
                 }
 
                 // warn!(" !!-=-=-!! Unknown notification closed, id: {id} | reason: {reason}, could not find matching id");
@@ -314,7 +362,6 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
     conn.start_receive(
         matchrule_notification_delivered,
         Box::new({
-            let keyboard_info = keyboard_info.clone();
             let progress_map = progress_map.clone();
             let notification_q = notification_q.clone();
             move |message: Message, _| {
@@ -329,7 +376,7 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
                             info!("Notification delivered, set its id to {id} | reply to {destination}");
                             let settings = &notif.settings;
                             if settings.flash_on_notify {
-                                flash_color(&keyboard_info, settings.color, 900, &progress_map, &notification_q);
+                                flash_color(&current_keyboard(), settings.color, 900, &progress_map, &notification_q);
                             }
                         },
                         None => {
@@ -349,16 +396,55 @@ pub fn process_dbus(config_j: &Value, keyboard_info: Arc<ZonedControllerInfo>) -
         }}),
     );
 
-    let mut last_user_idle = USER_IDLE.load(Ordering::Relaxed);
+    let mut last_idle_stage = USER_IDLE_STAGE.load(Ordering::Relaxed);
 
     loop {
         conn.process(Duration::from_millis(1000)).unwrap();
 
-        let user_idle = USER_IDLE.load(Ordering::Relaxed);
-        if user_idle != last_user_idle {
-            last_user_idle = user_idle;
-            info!("User idle state changed: {user_idle}");
-            composite(&keyboard_info, &progress_map, &notification_q, Some(1500));
+        let idle_stage = USER_IDLE_STAGE.load(Ordering::Relaxed);
+        if idle_stage != last_idle_stage {
+            last_idle_stage = idle_stage;
+            info!("User idle stage changed: {idle_stage}");
+            composite(
+                &current_keyboard(),
+                &progress_map,
+                &notification_q,
+                current_config().transitions.idle,
+            );
+        }
+
+        let generation = CONFIG_GENERATION.load(Ordering::Relaxed);
+        if generation != config_generation {
+            config_generation = generation;
+            load_maps(
+                &current_config(),
+                &notification_map,
+                &progress_map,
+                &language_color_map,
+            );
+            let profile_switch = current_config().transitions.profile_switch;
+            apply_keyboard_layout(&current_keyboard(), profile_switch);
+            info!("Applied the config or profile change");
+            // Crossfade into the new layout
+            composite(
+                &current_keyboard(),
+                &progress_map,
+                &notification_q,
+                profile_switch,
+            );
+        }
+
+        // The keyboard came back from a reconnect, draw everything again on its leds
+        let generation = KEYBOARD_GENERATION.load(Ordering::Relaxed);
+        if generation != keyboard_generation {
+            keyboard_generation = generation;
+            apply_keyboard_layout(&current_keyboard(), TransitionProfile::linear(0));
+            composite(
+                &current_keyboard(),
+                &progress_map,
+                &notification_q,
+                TransitionProfile::linear(0),
+            );
         }
 
         if ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 1 {
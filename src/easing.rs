@@ -0,0 +1,53 @@
+use std::f64::consts::PI;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    #[default]
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    ExponentialIn,
+    ExponentialOut,
+    ExponentialInOut,
+    // Overshoots the target a little and settles
+    Spring,
+    // Jumps to the target halfway through
+    Step,
+}
+
+impl Easing {
+    // Map the linear progress of a transition (0..1) to the eased one
+    pub fn apply(&self, t: f64) -> f64 {
+        let t = t.clamp(0.0, 1.0);
+        if t <= 0.0 {
+            return 0.0;
+        }
+        if t >= 1.0 {
+            return 1.0;
+        }
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => 1.0 - (t * PI / 2.0).cos(),
+            Easing::EaseOut => (t * PI / 2.0).sin(),
+            Easing::EaseInOut => (1.0 - (t * PI).cos()) / 2.0,
+            Easing::CubicIn => t.powi(3),
+            Easing::CubicOut => 1.0 - (1.0 - t).powi(3),
+            Easing::CubicInOut if t < 0.5 => 4.0 * t.powi(3),
+            Easing::CubicInOut => 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0,
+            Easing::ExponentialIn => 2.0_f64.powf(10.0 * t - 10.0),
+            Easing::ExponentialOut => 1.0 - 2.0_f64.powf(-10.0 * t),
+            Easing::ExponentialInOut if t < 0.5 => 2.0_f64.powf(20.0 * t - 10.0) / 2.0,
+            Easing::ExponentialInOut => (2.0 - 2.0_f64.powf(-20.0 * t + 10.0)) / 2.0,
+            Easing::Spring => 1.0 - (-6.0 * t).exp() * (12.0 * t).cos(),
+            Easing::Step if t < 0.5 => 0.0,
+            Easing::Step => 1.0,
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use std::f64::consts::TAU;
+
+use openrgb2::Color;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+
+use crate::{
+    colorspace::{dim, to_color, to_rgb},
+    config::{ColorRef, Theme},
+    consts::*,
+    utils::{lerp_color, ZonedControllerInfo},
+};
+
+// A procedural animation, rendered every frame
+pub trait Effect: Send + Sync {
+    // Colors of every led of the device, time is in seconds on a clock shared by all devices
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame;
+}
+
+// Effects that can be assigned in the config, "effect" picks one and the rest are its parameters
+#[derive(Deserialize, Clone)]
+#[serde(tag = "effect", rename_all = "snake_case")]
+pub enum EffectConfig {
+    Breathing(BreathingConfig),
+    RainbowWave(RainbowWaveConfig),
+    Ripple(RippleConfig),
+    Starfield(StarfieldConfig),
+    Fire(FireConfig),
+    GradientSweep(GradientSweepConfig),
+    DualSine(DualSineConfig),
+}
+
+impl EffectConfig {
+    pub fn build(&self, theme: &Theme) -> Box<dyn Effect> {
+        match self {
+            EffectConfig::Breathing(config) => Box::new(Breathing {
+                color: config.color.resolve(theme),
+                period: config.period_ms as f64 / 1000.0,
+                min_brightness: config.min_brightness,
+            }),
+            EffectConfig::RainbowWave(config) => Box::new(config.clone()),
+            EffectConfig::Ripple(config) => Box::new(Ripple {
+                color: config.color.resolve(theme),
+                background: config.background.resolve(theme),
+                speed: config.speed,
+                width: config.width,
+                interval: config.interval_ms as f64 / 1000.0,
+                desk: config.desk,
+                center: config
+                    .center
+                    .map(|[x, y]| (x / KEY_PITCH_MM, y / KEY_PITCH_MM)),
+            }),
+            EffectConfig::Starfield(config) => Box::new(Starfield {
+                color: config.color.resolve(theme),
+                background: config.background.resolve(theme),
+                density: config.density,
+                fade: config.fade_ms as f64 / 1000.0,
+                stars: Vec::new(),
+                last_time: None,
+                rng: StdRng::from_entropy(),
+            }),
+            EffectConfig::Fire(config) => Box::new(Fire {
+                cooling: config.cooling,
+                sparking: config.sparking,
+                heat: Vec::new(),
+                last_time: None,
+                rng: StdRng::from_entropy(),
+            }),
+            EffectConfig::GradientSweep(config) => Box::new(GradientSweep {
+                colors: config
+                    .colors
+                    .iter()
+                    .map(|color| color.resolve(theme))
+                    .collect(),
+                period: config.period_ms as f64 / 1000.0,
+            }),
+            EffectConfig::DualSine(config) => Box::new(DualSine {
+                color1: config.color1.resolve(theme),
+                color2: config.color2.resolve(theme),
+                speed1: config.speed1,
+                speed2: config.speed2,
+                wavelength: config.wavelength,
+            }),
+        }
+    }
+}
+
+impl Default for EffectConfig {
+    // The wave the backlight always had
+    fn default() -> Self {
+        EffectConfig::DualSine(DualSineConfig::default())
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreathingConfig {
+    pub color: ColorRef,
+    pub period_ms: u32,
+    pub min_brightness: f64,
+}
+
+impl Default for BreathingConfig {
+    fn default() -> Self {
+        BreathingConfig {
+            color: ColorRef::Theme("main".to_owned()),
+            period_ms: 4000,
+            min_brightness: 0.1,
+        }
+    }
+}
+
+struct Breathing {
+    color: Color,
+    period: f64,
+    min_brightness: f64,
+}
+
+impl Effect for Breathing {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        let wave = (1.0 - (time / self.period * TAU).cos()) / 2.0;
+        let brightness = self.min_brightness + (1.0 - self.min_brightness) * wave;
+        let color = to_color(dim(to_rgb(&self.color), brightness));
+        vec![color; device.total_leds]
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RainbowWaveConfig {
+    // Leds per full rainbow
+    pub wavelength: f64,
+    // Rainbows per second passing a led
+    pub speed: f64,
+    pub saturation: f64,
+    pub brightness: f64,
+}
+
+impl Default for RainbowWaveConfig {
+    fn default() -> Self {
+        RainbowWaveConfig {
+            wavelength: 20.0,
+            speed: 0.2,
+            saturation: 1.0,
+            brightness: 1.0,
+        }
+    }
+}
+
+impl Effect for RainbowWaveConfig {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        (0..device.total_leds)
+            .map(|index| {
+                let (x, _) = device.position(index);
+                let hue = (x / self.wavelength - time * self.speed).rem_euclid(1.0);
+                hsv_to_color(hue, self.saturation, self.brightness)
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RippleConfig {
+    pub color: ColorRef,
+    pub background: ColorRef,
+    // Leds per second
+    pub speed: f64,
+    // Leds
+    pub width: f64,
+    pub interval_ms: u32,
+    // Spread over the desk instead of the device, the same ring passes over every device that has it
+    pub desk: bool,
+    // Desk millimetres the desk ripple starts from, the center of the keyboard if not set
+    pub center: Option<[f64; 2]>,
+}
+
+impl Default for RippleConfig {
+    fn default() -> Self {
+        RippleConfig {
+            color: ColorRef::Literal(WHITE),
+            background: ColorRef::Literal(BLACK),
+            speed: 10.0,
+            width: 2.0,
+            interval_ms: 3000,
+            desk: false,
+            center: None,
+        }
+    }
+}
+
+struct Ripple {
+    color: Color,
+    background: Color,
+    speed: f64,
+    width: f64,
+    interval: f64,
+    desk: bool,
+    center: Option<(f64, f64)>,
+}
+
+impl Effect for Ripple {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        let radius = time.rem_euclid(self.interval) * self.speed;
+        let center = if self.desk {
+            self.center.unwrap_or(*KEYBOARD_DESK_CENTER.read().unwrap())
+        } else {
+            device.center()
+        };
+        (0..device.total_leds)
+            .map(|index| {
+                let (x, y) = if self.desk {
+                    device.desk_position(index)
+                } else {
+                    device.position(index)
+                };
+                let distance = ((x - center.0).powi(2) + (y - center.1).powi(2)).sqrt();
+                let ring = (-((distance - radius) / self.width).powi(2)).exp();
+                lerp_color(&self.background, &self.color, ring)
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StarfieldConfig {
+    pub color: ColorRef,
+    pub background: ColorRef,
+    // Stars lighting up per led and second
+    pub density: f64,
+    pub fade_ms: u32,
+}
+
+impl Default for StarfieldConfig {
+    fn default() -> Self {
+        StarfieldConfig {
+            color: ColorRef::Literal(WHITE),
+            background: ColorRef::Literal(BLACK),
+            density: 0.05,
+            fade_ms: 1500,
+        }
+    }
+}
+
+struct Starfield {
+    color: Color,
+    background: Color,
+    density: f64,
+    fade: f64,
+    // Brightness of the star on every led
+    stars: Vec<f64>,
+    // None until the first frame, the time is counted from the start of the daemon
+    last_time: Option<f64>,
+    rng: StdRng,
+}
+
+impl Effect for Starfield {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        let elapsed = self.last_time.map_or(0.0, |last| (time - last).max(0.0));
+        self.last_time = Some(time);
+        self.stars.resize(device.total_leds, 0.0);
+        for star in self.stars.iter_mut() {
+            *star = (*star - elapsed / self.fade).max(0.0);
+            if self.rng.gen::<f64>() < self.density * elapsed {
+                *star = 1.0;
+            }
+        }
+        (0..device.total_leds)
+            .map(|index| {
+                // Stars glow onto the keys around them, less the further away they are
+                let glow = device
+                    .neighbors(index)
+                    .into_iter()
+                    .map(|other| self.stars[other] / (1.0 + device.distance(index, other)).powi(2))
+                    .fold(0.0, f64::max);
+                lerp_color(&self.background, &self.color, self.stars[index].max(glow))
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FireConfig {
+    // How fast the flames cool down while rising
+    pub cooling: f64,
+    // Sparks per column and second
+    pub sparking: f64,
+}
+
+impl Default for FireConfig {
+    fn default() -> Self {
+        FireConfig {
+            cooling: 1.5,
+            sparking: 6.0,
+        }
+    }
+}
+
+struct Fire {
+    cooling: f64,
+    sparking: f64,
+    // Heat of every cell of the grid, row by row from the bottom
+    heat: Vec<f64>,
+    last_time: Option<f64>,
+    rng: StdRng,
+}
+
+impl Effect for Fire {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        let elapsed = self.last_time.map_or(0.0, |last| (time - last).max(0.0));
+        self.last_time = Some(time);
+        let (width, height) = (device.width.max(1), device.height.max(1));
+        self.heat.resize(width * height, 0.0);
+
+        for cell in self.heat.iter_mut() {
+            *cell = (*cell - self.rng.gen::<f64>() * self.cooling * elapsed).max(0.0);
+        }
+        // Heat rises, every cell takes after the ones below it
+        for y in (1..height).rev() {
+            for x in 0..width {
+                let below = self.heat[(y - 1) * width + x];
+                let further_below = self.heat[y.saturating_sub(2) * width + x];
+                let cell = &mut self.heat[y * width + x];
+                *cell = (*cell + below * 2.0 + further_below) / 4.0;
+            }
+        }
+        // New sparks on the bottom row
+        for cell in self.heat.iter_mut().take(width) {
+            if self.rng.gen::<f64>() < self.sparking * elapsed {
+                *cell = (*cell + self.rng.gen_range(0.6..1.0)).min(1.0);
+            }
+        }
+
+        (0..device.total_leds)
+            .map(|index| {
+                let cell = device.cell(index);
+                // Cells count rows from the top
+                let row = height - 1 - cell.y.min(height - 1);
+                heat_color(self.heat[row * width + cell.x.min(width - 1)])
+            })
+            .collect()
+    }
+}
+
+// Black, red, yellow, white
+fn heat_color(heat: f64) -> Color {
+    let heat = heat.clamp(0.0, 1.0) * 3.0;
+    if heat < 1.0 {
+        lerp_color(&BLACK, &RED, heat)
+    } else if heat < 2.0 {
+        lerp_color(&RED, &YELLOW, heat - 1.0)
+    } else {
+        lerp_color(&YELLOW, &WHITE, heat - 2.0)
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct GradientSweepConfig {
+    pub colors: Vec<ColorRef>,
+    pub period_ms: u32,
+}
+
+impl Default for GradientSweepConfig {
+    fn default() -> Self {
+        GradientSweepConfig {
+            colors: vec![
+                ColorRef::Theme("main".to_owned()),
+                ColorRef::Theme("function".to_owned()),
+                ColorRef::Theme("num_pad".to_owned()),
+            ],
+            period_ms: 6000,
+        }
+    }
+}
+
+struct GradientSweep {
+    colors: Vec<Color>,
+    period: f64,
+}
+
+impl Effect for GradientSweep {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        if self.colors.is_empty() {
+            return vec![BLACK; device.total_leds];
+        }
+        let count = self.colors.len();
+        (0..device.total_leds)
+            .map(|index| {
+                let (x, _) = device.position(index);
+                // Cycle through the colors across the device, wrapping around
+                let position = (x / device.size().0.max(1.0) + time / self.period).rem_euclid(1.0)
+                    * count as f64;
+                let from = position as usize % count;
+                lerp_color(
+                    &self.colors[from],
+                    &self.colors[(from + 1) % count],
+                    position.fract(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DualSineConfig {
+    pub color1: ColorRef,
+    pub color2: ColorRef,
+    // Radians per second of the traveling and the pulsing wave
+    pub speed1: f64,
+    pub speed2: f64,
+    // Leds per radian
+    pub wavelength: f64,
+}
+
+impl Default for DualSineConfig {
+    fn default() -> Self {
+        DualSineConfig {
+            color1: ColorRef::Theme("backlight_wave1".to_owned()),
+            color2: ColorRef::Theme("backlight_wave2".to_owned()),
+            speed1: 0.8,
+            speed2: 0.47,
+            wavelength: 4.0,
+        }
+    }
+}
+
+struct DualSine {
+    color1: Color,
+    color2: Color,
+    speed1: f64,
+    speed2: f64,
+    wavelength: f64,
+}
+
+impl Effect for DualSine {
+    fn render(&mut self, device: &ZonedControllerInfo, time: f64) -> Frame {
+        let offset = time * self.speed1;
+        let offset2 = 0.8 + time * self.speed2;
+        (0..device.total_leds)
+            .map(|index| {
+                let (x, _) = device.position(index);
+                lerp_color(
+                    &self.color1,
+                    &self.color2,
+                    ((x / self.wavelength + offset).sin() * offset2.sin() + 1.0) / 2.0,
+                )
+            })
+            .collect()
+    }
+}
+
+// Hue, saturation and value from 0 to 1
+fn hsv_to_color(hue: f64, saturation: f64, value: f64) -> Color {
+    let sector = hue.rem_euclid(1.0) * 6.0;
+    let chroma = value * saturation;
+    let second = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, second, 0.0),
+        1 => (second, chroma, 0.0),
+        2 => (0.0, chroma, second),
+        3 => (0.0, second, chroma),
+        4 => (second, 0.0, chroma),
+        _ => (chroma, 0.0, second),
+    };
+    let min = value - chroma;
+    Color {
+        r: ((r + min) * 255.0).round() as u8,
+        g: ((g + min) * 255.0).round() as u8,
+        b: ((b + min) * 255.0).round() as u8,
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{config::DeviceConfig, consts::KEY_PITCH_MM, utils::ZonedControllerInfo};
+
+// A key of a physical layout, in millimetres from the top left corner of the keyboard
+#[derive(Clone)]
+pub struct PhysicalKey {
+    // Led names the key can stand for, matched without case
+    pub names: Vec<String>,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl PhysicalKey {
+    pub fn center(&self) -> (f64, f64) {
+        (self.x + self.width / 2.0, self.y + self.height / 2.0)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct PhysicalLayout {
+    pub keys: Vec<PhysicalKey>,
+}
+
+// The native format: {"keys": {"Key: Escape": {"x": 0, "y": 0, "width": 18, "height": 18}, ...}}
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativeLayout {
+    keys: HashMap<String, NativeKey>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NativeKey {
+    x: f64,
+    y: f64,
+    #[serde(default = "key_pitch")]
+    width: f64,
+    #[serde(default = "key_pitch")]
+    height: f64,
+}
+
+fn key_pitch() -> f64 {
+    KEY_PITCH_MM
+}
+
+impl PhysicalLayout {
+    // Read a layout file, either the native format (an object) or keyboard-layout-editor JSON (an array)
+    pub fn load(path: &Path) -> Result<PhysicalLayout, Box<dyn Error>> {
+        let source = fs::read_to_string(path)
+            .map_err(|e| format!("could not read {}: {e}", path.display()))?;
+        let value: Value = serde_json::from_str(&source)
+            .map_err(|e| format!("{} is not valid json: {e}", path.display()))?;
+        match value {
+            Value::Array(rows) => Ok(PhysicalLayout::from_kle(&rows)),
+            value => {
+                let native: NativeLayout = serde_json::from_value(value)
+                    .map_err(|e| format!("{}: {e}", path.display()))?;
+                Ok(PhysicalLayout {
+                    keys: native
+                        .keys
+                        .into_iter()
+                        .map(|(name, key)| PhysicalKey {
+                            names: vec![name],
+                            x: key.x,
+                            y: key.y,
+                            width: key.width,
+                            height: key.height,
+                        })
+                        .collect(),
+                })
+            }
+        }
+    }
+
+    // Raw data of keyboard-layout-editor.com, key legends name the leds (with or without the "Key: " prefix),
+    // every line of a legend is tried. Rotated keys are placed as if they weren't
+    fn from_kle(rows: &[Value]) -> PhysicalLayout {
+        let mut keys = Vec::new();
+        let mut y = 0.0;
+        // Rows are arrays, the metadata object at the start is skipped
+        for row in rows.iter().filter_map(Value::as_array) {
+            let mut x = 0.0;
+            let (mut width, mut height) = (1.0, 1.0);
+            for item in row {
+                match item {
+                    Value::Object(props) => {
+                        let prop = |name: &str| props.get(name).and_then(Value::as_f64);
+                        x += prop("x").unwrap_or(0.0);
+                        y += prop("y").unwrap_or(0.0);
+                        width = prop("w").unwrap_or(width);
+                        height = prop("h").unwrap_or(height);
+                    }
+                    Value::String(legend) => {
+                        let names = legend
+                            .lines()
+                            .filter(|line| !line.is_empty())
+                            .flat_map(|line| [line.to_owned(), format!("Key: {line}")])
+                            .collect();
+                        keys.push(PhysicalKey {
+                            names,
+                            x: x * KEY_PITCH_MM,
+                            y: y * KEY_PITCH_MM,
+                            width: width * KEY_PITCH_MM,
+                            height: height * KEY_PITCH_MM,
+                        });
+                        x += width;
+                        // Sizes only apply to the next key
+                        (width, height) = (1.0, 1.0);
+                    }
+                    _ => {}
+                }
+            }
+            y += 1.0;
+        }
+        PhysicalLayout { keys }
+    }
+
+    // Center of every led in key units (millimetres / KEY_PITCH_MM), None for leds the layout doesn't have
+    pub fn place<'a>(&self, led_names: impl Iterator<Item = &'a str>) -> Vec<Option<(f64, f64)>> {
+        let mut centers: HashMap<String, (f64, f64)> = HashMap::new();
+        for key in &self.keys {
+            let (x, y) = key.center();
+            for name in &key.names {
+                centers
+                    .entry(name.to_lowercase())
+                    .or_insert((x / KEY_PITCH_MM, y / KEY_PITCH_MM));
+            }
+        }
+        led_names
+            .map(|name| centers.get(&name.to_lowercase()).copied())
+            .collect()
+    }
+}
+
+// Where a device sits on the desk, shared by all devices so effects can travel between them.
+// Millimetres, x to the right and y towards the user, the keyboard's top left corner is at 0, 0 by default
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlacementConfig {
+    // Of the device's first led (its top left corner for keyboards)
+    pub position: [f64; 2],
+    // Degrees clockwise around the position
+    pub rotation: f64,
+    // Points a linear zone (a strip) follows, relative to the position, the leds are spread evenly along it
+    pub path: Vec<[f64; 2]>,
+}
+
+impl PlacementConfig {
+    // Desk position of every led in key units (millimetres / KEY_PITCH_MM) from the local ones
+    pub fn place(&self, local: &[(f64, f64)], linear: bool) -> Vec<(f64, f64)> {
+        let local: Vec<(f64, f64)> = match (linear, self.path.len()) {
+            (true, 2..) => spread_along(&self.path, local.len()),
+            _ => local
+                .iter()
+                .map(|(x, y)| (x * KEY_PITCH_MM, y * KEY_PITCH_MM))
+                .collect(),
+        };
+        let (sin, cos) = self.rotation.to_radians().sin_cos();
+        let [origin_x, origin_y] = self.position;
+        local
+            .into_iter()
+            .map(|(x, y)| {
+                // y points down, so this turns clockwise on the desk
+                let (x, y) = (origin_x + x * cos - y * sin, origin_y + x * sin + y * cos);
+                (x / KEY_PITCH_MM, y / KEY_PITCH_MM)
+            })
+            .collect()
+    }
+}
+
+// Evenly spaced points along a polyline, from its start to its end
+fn spread_along(path: &[[f64; 2]], count: usize) -> Vec<(f64, f64)> {
+    let segments: Vec<([f64; 2], [f64; 2], f64)> = path
+        .windows(2)
+        .map(|pair| {
+            let ([x1, y1], [x2, y2]) = (pair[0], pair[1]);
+            (
+                pair[0],
+                pair[1],
+                ((x2 - x1).powi(2) + (y2 - y1).powi(2)).sqrt(),
+            )
+        })
+        .collect();
+    let length: f64 = segments.iter().map(|(_, _, length)| length).sum();
+    (0..count)
+        .map(|index| {
+            let mut along = match count {
+                1 => 0.0,
+                _ => length * index as f64 / (count - 1) as f64,
+            };
+            for ([x1, y1], [x2, y2], segment) in &segments {
+                if along <= *segment {
+                    let t = if *segment > 0.0 { along / segment } else { 0.0 };
+                    return (x1 + (x2 - x1) * t, y1 + (y2 - y1) * t);
+                }
+                along -= segment;
+            }
+            let [x, y] = path[path.len() - 1];
+            (x, y)
+        })
+        .collect()
+}
+
+// Where the physical layout of a device is, relative paths start at the config file
+pub fn geometry_path(device: &DeviceConfig, config_path: &Path) -> Option<PathBuf> {
+    let geometry = device.geometry.as_ref()?;
+    Some(config_path.parent().unwrap_or(Path::new("")).join(geometry))
+}
+
+// Place the leds of a device by its physical layout if it has one (keeping the matrix cells if it can't be loaded),
+// then on the desk
+pub fn attach_geometry(
+    info: ZonedControllerInfo,
+    device: &DeviceConfig,
+    config_path: &Path,
+) -> ZonedControllerInfo {
+    let info = match geometry_path(device, config_path) {
+        Some(path) => match PhysicalLayout::load(&path) {
+            Ok(layout) => {
+                info!(
+                    "Using the physical layout {} for {}",
+                    path.display(),
+                    device.name
+                );
+                info.with_physical_layout(&layout)
+            }
+            Err(e) => {
+                warn!("Ignoring the physical layout of {}: {e}", device.name);
+                info
+            }
+        },
+        None => info,
+    };
+    info.with_placement(&device.placement)
+}
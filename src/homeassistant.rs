@@ -4,44 +4,18 @@ use atomic::Ordering;
 use log::{info, warn};
 use serde_json::Value;
 
+use crate::compositor::set_ambient_dim;
+use crate::config::{current_config, HomeAssistantConfig};
 use crate::consts::*;
-use crate::enq_keyboard_frame;
-
-pub struct HomeAssistantConfig {
-    pub url: String,
-    pub token: String,
-
-    pub light_sensor_id: String,
-    pub lux_threshold: f64,
-    pub poll_interval_seconds: u64,
-    pub dim_brightness_mult: f64,
-}
-
-impl HomeAssistantConfig {
-    pub fn from_config(config_j: &Value) -> Option<HomeAssistantConfig> {
-        let ha = config_j.get("home_assistant")?;
-        if ha.is_null() {
-            return None;
-        }
-
-        let url = ha["url"].as_str()?.trim_end_matches('/').to_string();
-        let light_sensor_id = ha["sensor_id"].as_str()?.to_string();
-
-        Some(HomeAssistantConfig {
-            url,
-            light_sensor_id,
-            token: ha["token"].as_str().unwrap_or("").to_string(),
-            lux_threshold: ha["lux_threshold"].as_f64().unwrap_or(70.0),
-            poll_interval_seconds: ha["poll_interval_seconds"].as_u64().unwrap_or(30),
-            dim_brightness_mult: ha["dim_brightness_mult"].as_f64().unwrap_or(0.3).clamp(0.0, 1.0),
-        })
-    }
-}
 
 pub fn spawn_ambient_light_monitor(config: HomeAssistantConfig) {
     tokio::spawn(async move {
         let client = reqwest::Client::new();
-        let endpoint = format!("{}/api/states/{}", config.url, config.light_sensor_id);
+        let endpoint = format!(
+            "{}/api/states/{}",
+            config.url.trim_end_matches('/'),
+            config.light_sensor_id
+        );
 
         info!(
             "Home Assistant ambient light monitor started: {endpoint} (dim below {} lux to {:.2}x, polling every {}s)",
@@ -74,20 +48,18 @@ pub fn spawn_ambient_light_monitor(config: HomeAssistantConfig) {
     });
 }
 
-
 async fn fade_ambient_brightness(target: f64) {
-    const STEPS: u32 = 12;
+    let transition = current_config().transitions.ambient;
+    let steps = (transition.duration_ms / FRAME_DURATION_MS).max(1);
     let start = AMBIENT_BRIGHTNESS.load(Ordering::Relaxed);
+    // The keyboard layer animates by itself, the backlight follows the stepped value
+    set_ambient_dim(target, transition);
 
-    for step in 1..=STEPS {
-        let value = start + (target - start) * (step as f64 / STEPS as f64);
+    for step in 1..=steps {
+        let progress = transition.easing.apply(step as f64 / steps as f64);
+        let value = start + (target - start) * progress;
         AMBIENT_BRIGHTNESS.store(value, Ordering::Relaxed);
 
-        let last = KEYBOARD_LAST_FRAME.read().unwrap().clone();
-        if !last.is_empty() {
-            enq_keyboard_frame(last);
-        }
-
         tokio::time::sleep(Duration::from_millis(FRAME_DURATION_MS as u64)).await;
     }
 
@@ -1,66 +1,300 @@
+mod animations;
+mod check;
+mod cli;
+mod colorspace;
+mod compositor;
+mod config;
 mod consts;
 mod dbus;
+mod easing;
+mod effects;
+mod geometry;
 mod homeassistant;
+mod profile;
+mod reload;
+mod selector;
+mod snapshot;
 mod utils;
 mod wayland;
+use crate::animations::{cover_with_shutdown, AnimationConfig};
+use crate::check::check_config;
+use crate::cli::{Cli, Command};
+use crate::colorspace::{dim, to_rgb};
+use crate::compositor::*;
+use crate::config::*;
 use crate::consts::*;
 use crate::dbus::*;
+use crate::effects::Effect;
+use crate::geometry::attach_geometry;
 use crate::homeassistant::*;
+use crate::profile::*;
+use crate::reload::*;
+use crate::snapshot::{restore_devices, take_snapshot};
 use crate::utils::*;
 use crate::wayland::*;
 use atomic::Ordering;
+use clap::Parser;
 use log::warn;
 use log::{error, info};
-use openrgb2::Color;
 use openrgb2::Controller;
-use openrgb2::Led;
 use openrgb2::OpenRgbClient;
-use serde_json::Value;
 use signal_hook::consts::SIGTERM;
 use signal_hook::{consts::SIGINT, iterator::Signals};
 use std::error::Error;
-use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::vec;
+use tokio::task::JoinSet;
 use tokio::time::sleep;
 
-use rand::prelude::*;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
+    let cli = Cli::parse();
+    // The snapshot has everything, the config only names the client (the default one without a usable config)
+    if cli.restore {
+        let client_name = find_config_file(cli.config)
+            .ok()
+            .and_then(|path| load_config(&path).ok())
+            .map_or_else(
+                || OPENRGB_CLIENT_NAME.to_owned(),
+                |config| config.openrgb.client_name,
+            );
+        restore_devices(&client_name).await?;
+        return Ok(());
+    }
+
+    let config_path = match find_config_file(cli.config) {
+        Ok(path) => path,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if cli.print_config_path {
+        println!("{}", config_path.display());
+        return Ok(());
+    }
+    if let Some(Command::CheckConfig) = cli.command {
+        let usable = check_config(&config_path).await?;
+        std::process::exit(if usable { 0 } else { 1 });
+    }
+    info!("Using config {}", config_path.display());
+
+    // Read and validate the config before touching any device
+    let config = match load_config(&config_path) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
 
-    // connect to default server at localhost
-    let openrgb_client = get_openrgb_client("Custom effects client").await;
-    let controllers = openrgb_client.get_all_controllers().await?;
+    *ACTIVE_PROFILE.write().unwrap() = load_persisted_profile();
+    install_config(config.clone());
+    if let Some(profile) = active_profile_name(&config) {
+        info!("Using profile '{profile}'");
+    }
+    spawn_config_watcher(config_path.clone())?;
+    spawn_profile_switcher()?;
+
+    // The keyboard is needed to start, wait for it to be plugged in
+    let (session, keyboard_controller) = loop {
+        let session = match connect_devices(&config, &config_path).await {
+            Ok(session) => session,
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        };
+        match session.keyboard.clone() {
+            Some(keyboard) => break (session, keyboard),
+            None => sleep(Duration::from_millis(DEVICE_POLL_INTERVAL_MS)).await,
+        }
+    };
+
+    publish_keyboard(&keyboard_controller);
+    // Starting frame: full black
+    show_overlay(vec![BLACK; keyboard_controller.total_leds], 0);
+
+    spawn_wayland_monitor(
+        config
+            .idle
+            .stages
+            .iter()
+            .map(|stage| stage.timeout_ms)
+            .collect(),
+    );
+
+    match config.home_assistant.clone() {
+        Some(ha_config) => spawn_ambient_light_monitor(ha_config),
+        None => {
+            info!("No 'home_assistant' config section found; ambient light dimming unavailable")
+        }
+    }
+
+    apply_keyboard_layout(&keyboard_controller, TransitionProfile::linear(0));
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        signals.forever().next(); // Blocks until the signal is received
+        info!("Exiting main render loop...");
+        // The other devices play the animation in their own render loops
+        *SHUTDOWN_STARTED.write().unwrap() = Some(Instant::now());
+        let shutdown = current_config().animations.shutdown.clone();
+        if let Some(timing) = shutdown.timing() {
+            let steps = (timing.duration_ms / FRAME_DURATION_MS).max(1);
+            for step in 0..=steps {
+                // Played backwards, covering the keyboard
+                let progress = 1.0 - timing.easing.apply(step as f64 / steps as f64);
+                show_animation(&current_keyboard(), &shutdown, progress);
+                thread::sleep(Duration::from_millis(FRAME_DURATION_MS as u64));
+            }
+        }
+        show_overlay(
+            vec![BLACK; current_keyboard().total_leds],
+            FRAME_DURATION_MS,
+        );
+        // The render loop exits once the fade to black is done
+        ABOUT_TO_SHUTDOWN.store(1, Ordering::Relaxed);
+    });
+
+    spawn_device_supervisor(session, config_path);
+
+    // Startup animation, revealing the keyboard
+    let startup = config.animations.startup.clone();
+    match startup.timing() {
+        Some(_) if cli.skip_intro => info!("Skipping the startup animation"),
+        Some(timing) => {
+            let steps = (timing.duration_ms / FRAME_DURATION_MS).max(1);
+            for step in 0..=steps {
+                let progress = timing.easing.apply(step as f64 / steps as f64);
+                show_animation(&keyboard_controller, &startup, progress);
+                sleep(Duration::from_millis(FRAME_DURATION_MS as u64)).await;
+            }
+        }
+        None => {}
+    }
+    KEYBOARD_LAYERS.write().unwrap().remove(
+        &LayerId::Overlay,
+        TransitionProfile::linear(FRAME_DURATION_MS),
+    );
+
+    loop {
+        match process_dbus() {
+            Ok(_) => break,
+            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+        };
+    }
+    // Clean exit, give the devices back
+    if let Err(e) = restore_devices(&current_config().openrgb.client_name).await {
+        warn!("Could not restore the devices: {e}");
+    }
+    Ok(())
+}
+
+// A connection to one of the OpenRGB servers
+struct Server {
+    // Name in openrgb.servers, None for the main one
+    name: Option<String>,
+    client: Arc<OpenRgbClient>,
+}
+
+// Devices of one connection to the OpenRGB servers
+struct Session {
+    servers: Vec<Server>,
+    // Addresses of the servers from openrgb.servers that couldn't be reached, tried again while rendering
+    unreachable: Vec<String>,
+    // Can be unplugged, the layers keep going without it
+    keyboard: Option<Arc<ZonedControllerInfo>>,
+    devices: Vec<(DeviceRole, Arc<ZonedControllerInfo>)>,
+    // What the servers had when the session started, to notice devices coming and going
+    controllers: Vec<String>,
+}
+
+enum SessionEnd {
+    Shutdown,
+    DevicesChanged,
+    Lost(String),
+}
+
+// Controllers and their zones of a server
+fn controller_list<'a>(
+    server: Option<&str>,
+    controllers: impl Iterator<Item = &'a Controller>,
+) -> Vec<String> {
+    controllers
+        .flat_map(|controller| {
+            controller
+                .get_all_zones()
+                .map(|zone| {
+                    let server = server.unwrap_or_default();
+                    format!("{server} / {} / {}", controller.name(), zone.name())
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+// Connect to the servers and find the devices of the config by name, everything else is turned off
+async fn connect_devices(config: &Config, config_path: &Path) -> Result<Session, Box<dyn Error>> {
+    let openrgb = &config.openrgb;
+    // The main server is waited for, the devices of the others are skipped while they can't be reached
+    let main_address = openrgb.address();
+    let mut servers = vec![(
+        Server {
+            name: None,
+            client: Arc::new(
+                get_openrgb_client(&main_address, &openrgb.client_name)
+                    .await
+                    .ok_or("Shutting down")?,
+            ),
+        },
+        main_address,
+    )];
+    let mut unreachable = Vec::new();
+    for (name, server) in &openrgb.servers {
+        match try_connect_openrgb(&server.address(), &openrgb.client_name).await {
+            Ok(client) => {
+                info!(
+                    "Connected to the OpenRGB server {name} at {}",
+                    server.address()
+                );
+                let client = Arc::new(client);
+                let name = Some(name.clone());
+                servers.push((Server { name, client }, server.address()));
+            }
+            Err(e) => {
+                warn!("{e}, skipping the devices of {name}");
+                unreachable.push(server.address());
+            }
+        }
+    }
+
+    let mut controllers: Vec<(Option<String>, Arc<Controller>)> = Vec::new();
+    for (server, address) in &servers {
+        let found: Vec<Arc<Controller>> = server
+            .client
+            .get_all_controllers()
+            .await?
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        take_snapshot(address, &found);
+        controllers.extend(
+            found
+                .into_iter()
+                .map(|controller| (server.name.clone(), controller)),
+        );
+    }
 
     let mut keyboard_controller: Option<ZonedControllerInfo> = None;
-    let mut backlight_controller: Option<ZonedControllerInfo> = None;
-
-    // Read json config
-    let config_j: Value = serde_json::from_str(
-        fs::read_to_string("notification_config.json")
-            .expect("Error reading notification config")
-            .as_str(),
-    )?;
-
-    let keyboard_name = config_j["keyboard"]["name"]
-        .as_str()
-        .expect("Keyboard name missing");
-    let keyboard_zone = config_j["keyboard"]["zone"]
-        .as_str()
-        .expect("Keyboard zone missing");
-    let backlight_name = config_j["backlight"]["name"]
-        .as_str()
-        .expect("Backlight name missing");
-    let backlight_zone = config_j["backlight"]["zone"]
-        .as_str()
-        .expect("Backlight zone missing");
+    let mut devices: Vec<(DeviceRole, Arc<ZonedControllerInfo>)> = Vec::new();
 
     // query and print each controller data
-    for controller in controllers {
+    for (server, controller) in &controllers {
         info!(
             "[{:?}] Controller {}: {} | Zones: {:?}",
             controller.device_type(),
@@ -79,219 +313,313 @@ async fn main() -> Result<(), Box<dyn Error>> {
             controller.active_mode()
         );
 
-        if controller.name().eq(keyboard_name) {
-            turn_off_unused_zones(keyboard_zone, &controller).await?;
-            keyboard_controller = Some(ZonedControllerInfo::new(controller, keyboard_zone)?);
-        } else if controller.name().eq(backlight_name) {
-            turn_off_unused_zones(backlight_zone, &controller).await?;
-            backlight_controller = Some(ZonedControllerInfo::new(controller, backlight_zone)?);
-        } else {
-            turn_off_unused_zones("", &controller).await?;
+        // Everything that isn't shown goes black, including the devices with the off role
+        let used: Vec<&DeviceConfig> = config
+            .devices
+            .iter()
+            .filter(|device| {
+                device.server == *server
+                    && device.name == controller.name()
+                    && device.role != DeviceRole::Off
+            })
+            .collect();
+        let used_zones: Vec<&str> = used.iter().map(|device| device.zone.as_str()).collect();
+        turn_off_unused_zones(&used_zones, controller).await?;
+
+        for device in used {
+            match ZonedControllerInfo::new(controller.clone(), &device.zone) {
+                Ok(info) => {
+                    let info =
+                        attach_geometry(info, device, config_path).with_server(server.clone());
+                    match device.role {
+                        DeviceRole::Keyboard => keyboard_controller = Some(info),
+                        role => devices.push((role, Arc::new(info))),
+                    }
+                }
+                Err(e) if device.role == DeviceRole::Keyboard => return Err(e),
+                Err(e) => warn!("Skipping {}: {e}", device.name),
+            }
         }
     }
 
-    let keyboard_controller =
-        Arc::new(keyboard_controller.unwrap_or_else(|| panic!("{} not found!", keyboard_name)));
-
-    spawn_wayland_monitor();
-
-    match HomeAssistantConfig::from_config(&config_j) {
-        Some(ha_config) => spawn_ambient_light_monitor(ha_config),
-        None => info!("No 'home_assistant' config section found; ambient light dimming unavailable"),
-    }
-
-    let backlight_controller =
-        Arc::new(backlight_controller.unwrap_or_else(|| panic!("{} not found!", backlight_name)));
-
-    // Starting frame: full black
-    *KEYBOARD_BASE_FRAME.write().unwrap() = vec![BLACK; keyboard_controller.total_leds];
-    *KEYBOARD_IDLE_FRAME.write().unwrap() = vec![BLACK; keyboard_controller.total_leds];
-    *KEYBOARD_LAST_FRAME.write().unwrap() = vec![BLACK; keyboard_controller.total_leds];
-
-    // Target frame: colored according to my preferences
-    let keyboard_target_substrate = get_frame_by_key_names(
-        keyboard_controller.leds(),
-        Vec::from([
-            KeyMap {
-                keys: Vec::from(["Key: Number Pad", "Key: Num Lock"]),
-                color: NUM_PAD_COLOR,
-            },
-            KeyMap {
-                keys: Vec::from(["Insert", "Delete", "Page", "Arrow", "End", "Home"]),
-                color: FUNCTION_COLOR,
-            },
-            KeyMap {
-                keys: Vec::from(["Print", "Scroll", "Pause"]),
-                color: FUNCTION_COLOR2,
-            },
-        ]),
-        &|_: &Led, index: usize| match index <= 14 {
-            true => TOP_ROW_COLOR,
-            false => MAIN_COLOR,
-        },
-    );
-
-    let keyboard_idle_substrate = get_frame_by_key_names(
-        keyboard_controller.leds(),
-        Vec::from([
-            KeyMap {
-                keys: Vec::from([
-                    "Key: Number Pad",
-                    "Key: Num Lock",
-                    "Insert",
-                    "Delete",
-                    "Page",
-                    "Arrow",
-                    "End",
-                    "Home",
-                    "Print",
-                    "Scroll",
-                    "Pause",
-                ]),
-                color: BLACK,
-            },
-            KeyMap {
-                keys: Vec::from([
-                    "Key: `",
-                    "Key: 1",
-                    "Key: 2",
-                    "Key: 3",
-                    "Key: 4",
-                    "Key: 5",
-                    "Key: 6",
-                    "Key: 7",
-                    "Key: 8",
-                    "Key: 9",
-                    "Key: 0",
-                    "Key: -",
-                    "Key: =",
-                    "Key: Backspace",
-                ]),
-                color: IDLE_COLOR_NUMS,
-            },
-            KeyMap {
-                keys: Vec::from([
-                    "Key: Space"
-                ]),
-                color: IDLE_COLOR_SPACE,
-            },
-        ]),
-        &|_: &Led, index: usize| match index <= 14 {
-            true => BLACK,
-            false => IDLE_COLOR_BASE,
-        },
-    );
-
-    let mut signals = Signals::new([SIGINT, SIGTERM])?;
-    thread::spawn({
-        let keyboard_controller_arc = keyboard_controller.clone();
-
-        move || {
-            signals.forever().next(); // Blocks until the signal is received
-            info!("Exiting main render loop...");
-            let base = vec![BLACK; keyboard_controller_arc.total_leds];
-            let mut rng = rand::thread_rng();
-            for i in 1..7 {
-                let frame = base
-                    .iter()
-                    .map(|_| {
-                        let r: f64 = rng.gen();
-                        Color {
-                            b: 0,
-                            g: 0,
-                            r: (r / i as f64 * 255.0) as u8,
-                        }
-                    })
-                    .collect();
-                fade_into_frame(&frame, FRAME_DURATION_MS * 3);
-            }
-            fade_into_frame(&base, FRAME_DURATION_MS * 7);
-            ABOUT_TO_SHUTDOWN.store(1, Ordering::Relaxed);
+    for device in &config.devices {
+        let found = controllers.iter().any(|(server, controller)| {
+            *server == device.server && controller.name() == device.name
+        });
+        if !found && device.role != DeviceRole::Keyboard {
+            warn!("{} not found, skipping it", device.name);
         }
-    });
+    }
+    if keyboard_controller.is_none() {
+        warn!(
+            "Keyboard {} not found, waiting for it",
+            config.keyboard().name
+        );
+    }
 
-    tokio::spawn({
-        let keyboard_controller_arc = keyboard_controller.clone();
+    let mut controller_names: Vec<String> = controllers
+        .iter()
+        .flat_map(|(server, controller)| {
+            controller_list(server.as_deref(), std::iter::once(controller.as_ref()))
+        })
+        .collect();
+    controller_names.sort();
+    Ok(Session {
+        servers: servers.into_iter().map(|(server, _)| server).collect(),
+        unreachable,
+        keyboard: keyboard_controller.map(Arc::new),
+        devices,
+        controllers: controller_names,
+    })
+}
 
-        async move {
-            info!("Started main render loop");
-            match render_keyboard_frames(&keyboard_controller_arc).await {
-                Ok(_) => {
+// Keep the devices rendering, looking them up again when they are plugged in or out
+// and connecting again when the OpenRGB server goes away (restarts).
+// The layers outlive the sessions, so the keyboard picks up where it was
+fn spawn_device_supervisor(mut session: Session, config_path: PathBuf) {
+    tokio::spawn(async move {
+        loop {
+            match render_session(&session).await {
+                SessionEnd::Shutdown => {
                     info!("Main loop exited, exiting the program");
                     ABOUT_TO_SHUTDOWN.store(2, Ordering::Relaxed);
+                    return;
                 }
-                Err(e) => {
-                    error!("An error occurred in the frame rendering loop: {}", e);
+                SessionEnd::DevicesChanged => info!("The devices changed, looking them up again"),
+                SessionEnd::Lost(e) => {
+                    warn!("Lost the connection to OpenRGB ({e}), reconnecting");
+                    sleep_unless_shutdown(Duration::from_secs(3)).await;
+                }
+            }
+            session = loop {
+                // Nothing is left to fade out, let the exit path run
+                if ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
+                    info!("Shutting down while disconnected from OpenRGB");
+                    ABOUT_TO_SHUTDOWN.store(2, Ordering::Relaxed);
+                    return;
+                }
+                match connect_devices(&current_config(), &config_path).await {
+                    Ok(session) => {
+                        if let Some(keyboard) = &session.keyboard {
+                            publish_keyboard(keyboard);
+                        }
+                        break session;
+                    }
+                    Err(e) => {
+                        warn!("{e}, retrying in 3 seconds");
+                        sleep_unless_shutdown(Duration::from_secs(3)).await;
+                    }
                 }
             };
         }
     });
+}
 
-    tokio::spawn(async move {
-        info!("Started aux render loop");
+// Make the keyboard of a session the one everything draws on, the dbus loop redraws the layers on it.
+// A keyboard with a different number of leds starts over with an empty layer stack
+fn publish_keyboard(keyboard: &Arc<ZonedControllerInfo>) {
+    *KEYBOARD_DESK_CENTER.write().unwrap() = keyboard.desk_center();
+    let resized = KEYBOARD_LAYERS.read().unwrap().total_leds() != keyboard.total_leds;
+    if resized {
+        *KEYBOARD_LAYERS.write().unwrap() = LayerStack::new(keyboard.total_leds);
+        set_ambient_dim(
+            AMBIENT_BRIGHTNESS.load(Ordering::Relaxed),
+            TransitionProfile::linear(0),
+        );
+    }
+    *KEYBOARD.write().unwrap() = Some(keyboard.clone());
+    KEYBOARD_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
 
-        match render_table_backlight_frames(&backlight_controller).await {
-            Ok(_) => {}
-            Err(e) => {
-                error!("An error occurred in the aux frame rendering loop: {}", e);
+// Render all devices of a session until the keyboard loop exits (on shutdown), any loop fails
+// or the devices of the server change
+async fn render_session(session: &Session) -> SessionEnd {
+    let mut tasks = JoinSet::new();
+    if let Some(keyboard) = &session.keyboard {
+        let keyboard_controller_arc = keyboard.clone();
+        tasks.spawn(async move {
+            info!("Started main render loop");
+            match render_keyboard_frames(&keyboard_controller_arc).await {
+                Ok(_) => SessionEnd::Shutdown,
+                Err(e) => SessionEnd::Lost(format!("keyboard: {e}")),
             }
-        };
-    });
-
-    let keyboard_gray_substrate = vec![GRAY; keyboard_controller.total_leds];
+        });
+    }
 
-    for target_dist in 0..keyboard_controller.center_x * 3 {
-        let target_dist_f = target_dist as f64;
+    // One render loop per device
+    for (role, device) in &session.devices {
+        let (role, device) = (*role, device.clone());
+        let keyboard = session.keyboard.clone();
+        if role == DeviceRole::Mirror && keyboard.is_none() {
+            warn!(
+                "Not mirroring the keyboard on {}, it's not there",
+                device.name()
+            );
+            continue;
+        }
+        tasks.spawn(async move {
+            info!("Started the render loop of {} ({:?})", device.name(), role);
+            let result = match keyboard {
+                Some(keyboard) if role == DeviceRole::Mirror => {
+                    render_mirror_frames(&device, &keyboard).await
+                }
+                _ => render_ambient_frames(&device).await,
+            };
+            let error = result
+                .err()
+                .map_or_else(|| "stopped".to_owned(), |e| e.to_string());
+            SessionEnd::Lost(format!("{}: {error}", device.name()))
+        });
+    }
 
-        let intermediate: Frame = keyboard_gray_substrate
+    // Poll the device lists, OpenRGB changes them when devices are plugged in or out
+    tasks.spawn({
+        let servers: Vec<(Option<String>, Arc<OpenRgbClient>)> = session
+            .servers
             .iter()
-            .enumerate()
-            .map(|(index, gray)| {
-                let pos = keyboard_controller.num2xy(index);
-                let distance_from_center = (((pos.x as i64 - keyboard_controller.center_x as i64)
-                    .pow(2)
-                    + (pos.y as i64 - keyboard_controller.center_y as i64).pow(2))
-                    as f64)
-                    .sqrt();
-
-                // center color to gray
-                if distance_from_center < target_dist_f {
-                    let distance_factor = (distance_from_center - target_dist_f + 7.0) / 4.0; // 7 led offset from the center, 4 led width (offset from the edge)
-                    return lerp_color(&keyboard_target_substrate[index], gray, distance_factor);
+            .map(|server| (server.name.clone(), server.client.clone()))
+            .collect();
+        let unreachable = session.unreachable.clone();
+        let controllers = session.controllers.clone();
+        let has_keyboard = session.keyboard.is_some();
+        async move {
+            loop {
+                sleep(Duration::from_millis(DEVICE_POLL_INTERVAL_MS)).await;
+                // Nothing else notices the shutdown without the keyboard loop
+                if !has_keyboard && ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
+                    return SessionEnd::Shutdown;
+                }
+                let mut current = Vec::new();
+                for (name, client) in &servers {
+                    match client.get_all_controllers().await {
+                        Ok(found) => {
+                            let found: Vec<Controller> = found.into_iter().collect();
+                            current.extend(controller_list(name.as_deref(), found.iter()));
+                        }
+                        Err(e) => return SessionEnd::Lost(e.to_string()),
+                    }
+                }
+                current.sort();
+                if current != controllers {
+                    return SessionEnd::DevicesChanged;
+                }
+                // Servers that were down can be back
+                let client_name = current_config().openrgb.client_name.clone();
+                for address in &unreachable {
+                    if try_connect_openrgb(address, &client_name).await.is_ok() {
+                        return SessionEnd::DevicesChanged;
+                    }
                 }
+            }
+        }
+    });
 
-                let distance_factor = (distance_from_center - target_dist_f) / 2.0;
-                lerp_color(&WHITE, &BLACK, distance_factor)
-            })
-            .collect();
+    // The first one to end stops the others
+    let end = match tasks.join_next().await {
+        Some(Ok(end)) => end,
+        Some(Err(e)) => SessionEnd::Lost(e.to_string()),
+        None => SessionEnd::Shutdown,
+    };
+    tasks.abort_all();
+    end
+}
 
-        fade_into_frame(&intermediate, FRAME_DURATION_MS * 2) // stretch each frame 2 times
+// Compute the base frame, the frames of the idle stages and the language markers of the keyboard, redone whenever the config changes
+pub fn apply_keyboard_layout(
+    keyboard_controller: &ZonedControllerInfo,
+    transition: TransitionProfile,
+) {
+    let theme = THEME.read().unwrap().clone();
+    let config = current_config();
+    let layout = config.active_layout(active_profile(&config));
+
+    for warning in layout_warnings(keyboard_controller, layout) {
+        warn!("layout.{warning}");
     }
-
-    *KEYBOARD_BASE_FRAME.write().unwrap() = keyboard_target_substrate;
-    *KEYBOARD_IDLE_FRAME.write().unwrap() = keyboard_idle_substrate;
-
-    loop {
-        match process_dbus(&config_j, keyboard_controller.clone()) {
-            Ok(_) => return Ok(()),
-            Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+    *KEYBOARD_BASE_FRAME.write().unwrap() =
+        substrate_frame(keyboard_controller, &layout.active, &theme);
+    *KEYBOARD_IDLE_FRAMES.write().unwrap() = config
+        .idle
+        .stages
+        .iter()
+        .map(|stage| {
+            let substrate = stage.layout.as_ref().unwrap_or(&layout.idle);
+            substrate_frame(keyboard_controller, substrate, &theme)
+        })
+        .collect();
+    *LANGUAGE_MARKER_LEDS.write().unwrap() = layout
+        .language_markers
+        .resolve(keyboard_controller.leds().map(|(_, led)| led.name.as_str()));
+
+    let mut layers = KEYBOARD_LAYERS.write().unwrap();
+    layers.set(
+        LayerId::Base,
+        Layer::new(&LayerId::Base, KEYBOARD_BASE_FRAME.read().unwrap().clone()),
+        transition,
+    );
+    // Static layers from the config, the ones that are gone fade out
+    layers.remove_where(
+        |id| match id {
+            LayerId::External(name) => !layout.layers.iter().any(|layer| &layer.name == name),
+            _ => false,
+        },
+        transition,
+    );
+    for layer_config in &layout.layers {
+        let id = LayerId::External(layer_config.name.clone());
+        let mut effect = layer_config
+            .effect
+            .as_ref()
+            .map(|effect| effect.build(&theme));
+        let pixels = match (&mut effect, &layer_config.color) {
+            (Some(effect), _) => effect.render(keyboard_controller, 0.0),
+            (None, Some(color)) => vec![color.resolve(&theme); layers.total_leds()],
+            (None, None) => vec![BLACK; layers.total_leds()],
         };
+        let leds = layer_config
+            .keys
+            .resolve(keyboard_controller.leds().map(|(_, led)| led.name.as_str()));
+        let mut layer = Layer::sparse(
+            &id,
+            layers.total_leds(),
+            leds.into_iter()
+                .filter_map(|index| Some((index, *pixels.get(index)?, 1.0))),
+        )
+        .with_opacity(layer_config.opacity);
+        layer.blend = layer_config.blend;
+        layer.z = layer_config.z.unwrap_or(layer.z);
+        layers.set(id.clone(), layer, transition);
+        layers.set_effect(id, effect);
+    }
+
+    // Effect over the whole keyboard
+    match &config.keyboard().effect {
+        Some(effect) => {
+            let mut effect = effect.build(&theme);
+            let frame = effect.render(keyboard_controller, 0.0);
+            layers.set(
+                LayerId::Effect,
+                Layer::new(&LayerId::Effect, frame),
+                transition,
+            );
+            layers.set_effect(LayerId::Effect, Some(effect));
+        }
+        None => layers.remove(&LayerId::Effect, transition),
     }
 }
 
 async fn turn_off_unused_zones(
-    whitelisted_zone: &str,
+    used_zones: &[&str],
     controller: &Controller,
 ) -> Result<(), Box<dyn Error>> {
-    if controller.get_all_zones().count() == 1 && whitelisted_zone.is_empty() {
+    if used_zones.is_empty() {
         info!("Turning off controller: {}", controller.name());
         controller.set_all_leds(BLACK).await?;
         return Ok(());
     }
-    for (_, z) in controller
+    for z in controller
         .get_all_zones()
-        .enumerate()
-        .filter(|(_, z)| z.name().ne(whitelisted_zone))
+        .filter(|z| !used_zones.contains(&z.name()))
     {
         info!(
             "Turning off zone '{}' of controller: '{}'",
@@ -303,105 +631,181 @@ async fn turn_off_unused_zones(
     Ok(())
 }
 
-fn dim_frame(frame: Frame) -> Frame {
-    let factor = AMBIENT_BRIGHTNESS.load(Ordering::Relaxed);
-    if factor >= 1.0 {
-        return frame;
-    }
-    frame.iter().map(|color| lerp_color(&BLACK, color, factor)).collect()
+// Cover the whole keyboard with a frame (intro and exit animations), still dimmed by the ambient light
+fn show_overlay(frame: Frame, fade_time_ms: u32) {
+    KEYBOARD_LAYERS.write().unwrap().set(
+        LayerId::Overlay,
+        Layer::new(&LayerId::Overlay, frame),
+        TransitionProfile::linear(fade_time_ms),
+    );
 }
 
-fn enq_keyboard_frame(frame: Frame) {
-    *KEYBOARD_LAST_FRAME.write().unwrap() = frame.clone();
-    match KEYBOARD_FRAME_Q.push(frame) {
-        Ok(_) => {}
-        Err(e) => {
-            error!("Error adding frame! ({})", e);
-        }
-    }
+// Show a moment of a startup or shutdown animation on the keyboard
+fn show_animation(keyboard: &ZonedControllerInfo, animation: &AnimationConfig, progress: f64) {
+    let theme = THEME.read().unwrap().clone();
+    let (frame, mask) = animation
+        .overlay(keyboard, &theme, progress)
+        .into_iter()
+        .unzip();
+    KEYBOARD_LAYERS.write().unwrap().set(
+        LayerId::Overlay,
+        Layer::masked(&LayerId::Overlay, frame, mask),
+        TransitionProfile::linear(FRAME_DURATION_MS),
+    );
 }
 
 async fn render_keyboard_frames(controller: &ZonedControllerInfo) -> Result<(), Box<dyn Error>> {
     let frame_delay = Duration::from_millis(FRAME_DURATION_MS as u64);
+    let mut last_frame: Frame = Vec::new();
+    let mut ditherer = Ditherer::default();
     loop {
-        match KEYBOARD_FRAME_Q.pop() {
-            Ok(frame) => controller.zone().set_leds(dim_frame(frame)).await?,
-            Err(_) => {
-                if ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
-                    // Exit the loop, we need to shutdown
-                    return Ok(());
-                }
-            }
+        // Sample the layers every frame, only send what changed
+        let (frame, animating) = {
+            let mut layers = KEYBOARD_LAYERS.write().unwrap();
+            layers.run_effects(controller, RENDER_START.elapsed().as_secs_f64());
+            (layers.flatten(), layers.is_animating())
+        };
+        KEYBOARD_FRAME.write().unwrap().clone_from(&frame);
+        let frame = ditherer.dither(&frame);
+        if frame != last_frame {
+            controller.zone().set_leds(frame.clone()).await?;
+            last_frame = frame;
+        }
+        if !animating && ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
+            // Exit the loop, we need to shutdown
+            return Ok(());
         }
 
         sleep(frame_delay).await;
     }
 }
 
-async fn render_table_backlight_frames(
-    backlight_controller: &ZonedControllerInfo,
-) -> Result<(), Box<dyn Error>> {
+// A device showing its own effect, dimmed along with the keyboard
+async fn render_ambient_frames(device: &ZonedControllerInfo) -> Result<(), Box<dyn Error>> {
     let frame_delay = Duration::from_millis(FRAME_DURATION_MS as u64);
-    let base: Vec<Color> = vec![BLACK; backlight_controller.total_leds];
-
-    fn generate_frame(offset: f64, offset2: f64, brightness: f64, base: &[Color]) -> Frame {
-        base.iter()
-            .enumerate()
-            .map(|(index, _)| {
-                lerp_color(
-                    &BLACK,
-                    &lerp_color(
-                        &BACKLIGHT_WAVE1_COLOR,
-                        &BACKLIGHT_WAVE2_COLOR,
-                        ((index as f64 / 4.0 + offset).sin() * offset2.sin() + 1.0) / 2.0,
-                    ),
-                    brightness,
-                )
-            })
+
+    // The configured effect, or the wave in the colors of the theme
+    let build_effect = || -> Box<dyn Effect> {
+        current_config()
+            .device(device.server(), device.name(), device.zone_name())
+            .and_then(|config| config.effect.clone())
+            .unwrap_or_default()
+            .build(&THEME.read().unwrap())
+    };
+
+    fn dim_frame(frame: &Frame, brightness: f64) -> WideFrame {
+        // Dim in full precision (in linear light), the dark end is dithered
+        frame
+            .iter()
+            .map(|color| WideColor::from_rgb(dim(to_rgb(color), brightness)))
             .collect()
     }
 
-    let mut offset = 0.0;
-    let mut offset2 = 0.8;
+    let mut effect = build_effect();
+    let mut config_generation = CONFIG_GENERATION.load(Ordering::Relaxed);
     let mut brightness = 0.0;
+    let mut ditherer = Ditherer::default();
 
     loop {
-        offset += 0.06;
-        offset2 += 0.035;
-        if SCREEN_LOCKED.load(Ordering::Relaxed) || USER_IDLE.load(Ordering::Relaxed) {
+        // Pick up theme, profile and config changes
+        let generation = CONFIG_GENERATION.load(Ordering::Relaxed);
+        if generation != config_generation {
+            config_generation = generation;
+            effect = build_effect();
+        }
+        if SCREEN_LOCKED.load(Ordering::Relaxed)
+            || USER_IDLE_STAGE.load(Ordering::Relaxed) > 0
+            || !BACKLIGHT_WAVE_ENABLED.load(Ordering::Relaxed)
+        {
             brightness -= 0.07_f64
-        } else if ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
-            brightness -= 0.1_f64
         } else {
             brightness += 0.07_f64
         }
         brightness = brightness.clamp(0.0, 1.0);
         // Scale by the ambient-light multiplier so the backlight dims in the dark too.
         let effective_brightness = brightness * AMBIENT_BRIGHTNESS.load(Ordering::Relaxed);
-        if effective_brightness > 0.0 {
-            backlight_controller
-                .zone()
-                .set_leds(generate_frame(offset, offset2, effective_brightness, &base))
-                .await?;
+        let shutdown_started = *SHUTDOWN_STARTED.read().unwrap();
+        if effective_brightness > 0.0 || shutdown_started.is_some() {
+            let frame = effect.render(device, RENDER_START.elapsed().as_secs_f64());
+            let mut frame = dim_frame(&frame, effective_brightness);
+            if let Some(started) = shutdown_started {
+                cover_with_shutdown(device, &mut frame, started);
+            }
+            device.zone().set_leds(ditherer.dither(&frame)).await?;
         }
         sleep(frame_delay).await;
     }
 }
 
-async fn get_openrgb_client(name: &str) -> OpenRgbClient {
+// A device showing the keyboard, scaled to its size
+async fn render_mirror_frames(
+    device: &ZonedControllerInfo,
+    keyboard: &ZonedControllerInfo,
+) -> Result<(), Box<dyn Error>> {
+    let frame_delay = Duration::from_millis(FRAME_DURATION_MS as u64);
+    // Keyboard led every led of the device copies
+    let (width, height) = device.size();
+    let (keyboard_width, keyboard_height) = keyboard.size();
+    let sources: Vec<Option<usize>> = (0..device.total_leds)
+        .map(|index| {
+            let (x, y) = device.position(index);
+            keyboard.nearest_led((
+                x / width.max(1.0) * keyboard_width,
+                y / height.max(1.0) * keyboard_height,
+            ))
+        })
+        .collect();
+    let mut last_frame: Frame = Vec::new();
+    let mut ditherer = Ditherer::default();
+
     loop {
-        match OpenRgbClient::connect().await {
-            Ok(mut cl) => {
-                cl.set_name(name)
-                    .await
-                    .expect("Failed setting openrgb client name");
-                info!("Connected to openrgb with name: {name}!");
-                return cl;
+        let frame: WideFrame = {
+            let keyboard_frame = KEYBOARD_FRAME.read().unwrap();
+            sources
+                .iter()
+                .map(|source| {
+                    source
+                        .and_then(|index| keyboard_frame.get(index).cloned())
+                        .unwrap_or(WideColor::BLACK)
+                })
+                .collect()
+        };
+        let frame = ditherer.dither(&frame);
+        if frame != last_frame {
+            device.zone().set_leds(frame.clone()).await?;
+            last_frame = frame;
+        }
+        sleep(frame_delay).await;
+    }
+}
+
+// None when the daemon starts shutting down before the server is reachable
+async fn get_openrgb_client(address: &str, name: &str) -> Option<OpenRgbClient> {
+    loop {
+        if ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) > 0 {
+            return None;
+        }
+        match connect_openrgb(address, name).await {
+            Ok(cl) => {
+                info!("Connected to openrgb at {address} with name: {name}!");
+                return Some(cl);
             }
             Err(e) => {
                 warn!("{}, retrying in 3 seconds", e);
-                tokio::time::sleep(Duration::from_secs(3)).await
+                sleep_unless_shutdown(Duration::from_secs(3)).await
             }
         };
     }
 }
+
+// Sleep, waking up early when the daemon starts shutting down
+async fn sleep_unless_shutdown(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while ABOUT_TO_SHUTDOWN.load(Ordering::Relaxed) == 0 {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return;
+        }
+        sleep(left.min(Duration::from_millis(100))).await;
+    }
+}
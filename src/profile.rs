@@ -0,0 +1,106 @@
+use std::{env, fs, path::PathBuf, sync::atomic::Ordering, thread};
+
+use log::{info, warn};
+use signal_hook::{
+    consts::{SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+
+use crate::{
+    config::{current_config, Config, Profile},
+    consts::*,
+};
+
+// Name of the profile in use: the one switched to last if it still exists, otherwise the default one
+pub fn active_profile_name(config: &Config) -> Option<String> {
+    ACTIVE_PROFILE
+        .read()
+        .unwrap()
+        .clone()
+        .filter(|name| config.profiles.contains_key(name))
+        .or_else(|| config.default_profile.clone())
+}
+
+pub fn active_profile(config: &Config) -> Option<&Profile> {
+    active_profile_name(config).and_then(|name| config.profiles.get(&name))
+}
+
+// Update the global state that depends on the active profile
+pub fn apply_profile(config: &Config) {
+    let profile = active_profile(config);
+    *THEME.write().unwrap() = config.active_theme(profile);
+    BACKLIGHT_WAVE_ENABLED.store(
+        profile.is_none_or(|profile| profile.backlight_wave),
+        Ordering::Relaxed,
+    );
+}
+
+// A file under $XDG_STATE_HOME/keyboard_vis
+pub fn state_file_path(file_name: &str) -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join(CONFIG_DIR_NAME).join(file_name))
+}
+
+// The profile that was active when the daemon last ran
+pub fn load_persisted_profile() -> Option<String> {
+    let name = fs::read_to_string(state_file_path(PROFILE_STATE_FILE_NAME)?).ok()?;
+    let name = name.trim();
+    (!name.is_empty()).then(|| name.to_owned())
+}
+
+fn persist_profile(name: &str) {
+    let Some(path) = state_file_path(PROFILE_STATE_FILE_NAME) else {
+        return;
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, name));
+    if let Err(e) = result {
+        warn!(
+            "Could not save the active profile to {}: {e}",
+            path.display()
+        );
+    }
+}
+
+// Move through the profiles in alphabetical order
+fn switch_profile(step: isize) {
+    let config = current_config();
+    let names: Vec<&String> = config.profiles.keys().collect();
+    if names.is_empty() {
+        warn!("No profiles are configured, nothing to switch to");
+        return;
+    }
+
+    let current = active_profile_name(&config);
+    let next = match names
+        .iter()
+        .position(|name| Some(*name) == current.as_ref())
+    {
+        Some(position) => (position as isize + step).rem_euclid(names.len() as isize) as usize,
+        None => 0,
+    };
+    let name = names[next].to_owned();
+
+    info!("Switching to profile '{name}'");
+    *ACTIVE_PROFILE.write().unwrap() = Some(name.clone());
+    persist_profile(&name);
+    apply_profile(&config);
+    // The dbus loop picks the change up and crossfades into the new layout
+    CONFIG_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// SIGUSR1 switches to the next profile, SIGUSR2 to the previous one
+pub fn spawn_profile_switcher() -> Result<(), Box<dyn std::error::Error>> {
+    let mut signals = Signals::new([SIGUSR1, SIGUSR2])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            switch_profile(if signal == SIGUSR1 { 1 } else { -1 });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,84 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    thread,
+    time::Duration,
+};
+
+use inotify::{Inotify, WatchMask};
+use log::{info, warn};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+use crate::{
+    config::{load_config, Config},
+    consts::*,
+    profile::apply_profile,
+};
+
+// Make the config (and the theme of its active profile) the current one
+pub fn install_config(config: Arc<Config>) {
+    apply_profile(&config);
+    *COLOR_SPACE.write().unwrap() = config.rendering.color_space;
+    DITHERING.store(config.rendering.dithering, Ordering::Relaxed);
+    *CONFIG.write().unwrap() = Some(config);
+    CONFIG_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+// Load the config again and hand it over to the running loops, keep the old one if it's broken
+pub fn reload_config(path: &Path) {
+    match load_config(path) {
+        Ok(config) => {
+            install_config(Arc::new(config));
+            info!("Reloaded config from {}", path.display());
+        }
+        Err(e) => warn!("Keeping the old config, the new one is invalid. {e}"),
+    }
+}
+
+fn watch_config_file(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    // Watch the directory, editors usually replace the file instead of writing into it
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name().ok_or("Config path has no file name")?;
+
+    let mut inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+    info!("Watching {} for changes", path.display());
+
+    let mut buffer = [0; 4096];
+    loop {
+        let changed = inotify
+            .read_events_blocking(&mut buffer)?
+            .any(|event| event.name == Some(file_name));
+        if changed {
+            // Let the editor finish shuffling files around
+            thread::sleep(Duration::from_millis(100));
+            reload_config(path);
+        }
+    }
+}
+
+pub fn spawn_config_watcher(path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn({
+        let path = path.clone();
+        move || {
+            for _ in signals.forever() {
+                info!("SIGHUP received, reloading config");
+                reload_config(&path);
+            }
+        }
+    });
+
+    thread::spawn(move || {
+        if let Err(e) = watch_config_file(&path) {
+            warn!("Config file watcher unavailable: {e}");
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+
+#[derive(Clone)]
+enum Matcher {
+    Exact(String),
+    Glob(Regex),
+    Regex(Regex),
+}
+
+#[derive(Clone)]
+struct Pattern {
+    text: String,
+    exclude: bool,
+    matcher: Matcher,
+}
+
+impl Pattern {
+    // "Key: Space" is an exact name, "Key: F*" a glob (* and ?), "/^Key: F\d+$/" a regex,
+    // a leading ! excludes the leds matched by the rest of the pattern
+    fn parse(text: &str) -> Result<Pattern, regex::Error> {
+        let (exclude, body) = match text.strip_prefix('!') {
+            Some(body) => (true, body),
+            None => (false, text),
+        };
+
+        let matcher = if body.len() >= 2 && body.starts_with('/') && body.ends_with('/') {
+            Matcher::Regex(Regex::new(&body[1..body.len() - 1])?)
+        } else if body.contains(['*', '?']) {
+            let glob = body
+                .split_inclusive(['*', '?'])
+                .map(|part| match part.strip_suffix('*') {
+                    Some(literal) => format!("{}.*", regex::escape(literal)),
+                    None => match part.strip_suffix('?') {
+                        Some(literal) => format!("{}.", regex::escape(literal)),
+                        None => regex::escape(part),
+                    },
+                })
+                .collect::<String>();
+            Matcher::Glob(Regex::new(&format!("^{glob}$"))?)
+        } else {
+            Matcher::Exact(body.to_owned())
+        };
+
+        Ok(Pattern {
+            text: text.to_owned(),
+            exclude,
+            matcher,
+        })
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match &self.matcher {
+            Matcher::Exact(exact) => exact == name,
+            Matcher::Glob(regex) | Matcher::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+// A set of led name patterns, a led is selected if any pattern includes it and no pattern excludes it
+#[derive(Clone)]
+pub struct KeySelector {
+    patterns: Vec<Pattern>,
+}
+
+impl KeySelector {
+    pub fn new(patterns: &[&str]) -> Result<KeySelector, regex::Error> {
+        Ok(KeySelector {
+            patterns: patterns
+                .iter()
+                .map(|pattern| Pattern::parse(pattern))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        let mut included = false;
+        for pattern in &self.patterns {
+            if pattern.matches(name) {
+                if pattern.exclude {
+                    return false;
+                }
+                included = true;
+            }
+        }
+        included
+    }
+
+    // Indexes of every selected led
+    pub fn resolve<'a>(&self, names: impl Iterator<Item = &'a str>) -> Vec<usize> {
+        names
+            .enumerate()
+            .filter(|(_, name)| self.matches(name))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Describe patterns that match nothing or suspiciously many leds.
+    // Without an explicit limit, a glob or a regex is suspicious if it takes more than a quarter of the zone
+    pub fn warnings(&self, names: &[&str], max_leds: Option<usize>) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for pattern in &self.patterns {
+            let count = names.iter().filter(|name| pattern.matches(name)).count();
+            let limit = match (&pattern.matcher, max_leds) {
+                (_, Some(max_leds)) => max_leds,
+                (Matcher::Exact(_), None) => 1,
+                (_, None) => (names.len() / 4).max(1),
+            };
+            if count == 0 {
+                warnings.push(format!("'{}' matches no leds", pattern.text));
+            } else if count > limit && !pattern.exclude {
+                warnings.push(format!(
+                    "'{}' matches {count} of {} leds",
+                    pattern.text,
+                    names.len()
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+impl<'de> Deserialize<'de> for KeySelector {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let patterns = Vec::<String>::deserialize(deserializer)?;
+        KeySelector::new(&patterns.iter().map(String::as_str).collect::<Vec<_>>())
+            .map_err(|e| serde::de::Error::custom(format!("invalid key pattern: {e}")))
+    }
+}
@@ -0,0 +1,188 @@
+use std::{error::Error, fs, sync::Arc};
+
+use log::{info, warn};
+use openrgb2::{Color, Controller};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    consts::{OPENRGB_DEFAULT_HOST, OPENRGB_DEFAULT_PORT, SNAPSHOT_FILE_NAME},
+    profile::state_file_path,
+    utils::connect_openrgb,
+};
+
+// How a controller was before it was switched to the controllable mode
+#[derive(Serialize, Deserialize, Clone)]
+struct ControllerSnapshot {
+    // host:port of the OpenRGB server it is on, snapshots of older versions were all of the default one
+    #[serde(default = "default_server")]
+    server: String,
+    name: String,
+    // Tells identical devices apart, the number of controllers with the same name listed before it
+    #[serde(default)]
+    ordinal: usize,
+    mode: usize,
+    colors: Vec<[u8; 3]>,
+}
+
+// Kept in a file so the devices can be restored after a crash too (--restore)
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    controllers: Vec<ControllerSnapshot>,
+}
+
+fn default_server() -> String {
+    format!("{OPENRGB_DEFAULT_HOST}:{OPENRGB_DEFAULT_PORT}")
+}
+
+fn load_snapshot() -> Snapshot {
+    let path = state_file_path(SNAPSHOT_FILE_NAME);
+    let Some(source) = path.and_then(|path| fs::read_to_string(path).ok()) else {
+        return Snapshot::default();
+    };
+    serde_json::from_str(&source).unwrap_or_else(|e| {
+        warn!("Ignoring the broken device snapshot: {e}");
+        Snapshot::default()
+    })
+}
+
+fn save_snapshot(snapshot: &Snapshot) {
+    let Some(path) = state_file_path(SNAPSHOT_FILE_NAME) else {
+        return;
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, serde_json::to_string(snapshot).unwrap_or_default()));
+    if let Err(e) = result {
+        warn!(
+            "Could not save the device snapshot to {}: {e}",
+            path.display()
+        );
+    }
+}
+
+// Controllers with their ordinal among the ones with the same name
+fn numbered(controllers: &[Arc<Controller>]) -> Vec<(usize, &Arc<Controller>)> {
+    controllers
+        .iter()
+        .enumerate()
+        .map(|(i, controller)| {
+            let ordinal = controllers[..i]
+                .iter()
+                .filter(|other| other.name() == controller.name())
+                .count();
+            (ordinal, controller)
+        })
+        .collect()
+}
+
+// Remember the mode and colors of the controllers that aren't in the snapshot yet, before they are taken over.
+// The ones that are were left that way by the last run (it crashed), they keep what they had before it
+pub fn take_snapshot(server: &str, controllers: &[Arc<Controller>]) {
+    let mut snapshot = load_snapshot();
+    let mut changed = false;
+    for (ordinal, controller) in numbered(controllers) {
+        if snapshot.controllers.iter().any(|saved| {
+            saved.server == server && saved.name == controller.name() && saved.ordinal == ordinal
+        }) {
+            continue;
+        }
+        snapshot.controllers.push(ControllerSnapshot {
+            server: server.to_owned(),
+            name: controller.name().to_owned(),
+            ordinal,
+            mode: controller.active_mode().id(),
+            colors: controller
+                .colors()
+                .iter()
+                .map(|color| [color.r, color.g, color.b])
+                .collect(),
+        });
+        changed = true;
+    }
+    if changed {
+        save_snapshot(&snapshot);
+    }
+}
+
+// Put the controllers of a server back how they were and forget them, controllers that aren't there stay in the
+// snapshot. So do the ones of the servers that couldn't be reached
+async fn restore_snapshot(
+    snapshot: &mut Snapshot,
+    server: &str,
+    controllers: &[Arc<Controller>],
+) -> Result<(), Box<dyn Error>> {
+    let controllers = numbered(controllers);
+    let mut missing = Vec::new();
+    for saved in snapshot.controllers.drain(..) {
+        if saved.server != server {
+            missing.push(saved);
+            continue;
+        }
+        let Some((_, controller)) = controllers.iter().find(|(ordinal, controller)| {
+            controller.name() == saved.name && *ordinal == saved.ordinal
+        }) else {
+            missing.push(saved);
+            continue;
+        };
+        info!("Restoring {}", saved.name);
+        // Colors first, they only show in the modes that use them
+        let colors = saved
+            .colors
+            .iter()
+            .map(|&[r, g, b]| Color { r, g, b })
+            .collect();
+        controller.set_leds(colors).await?;
+        controller.set_active_mode(saved.mode).await?;
+    }
+    snapshot.controllers = missing;
+    Ok(())
+}
+
+// Restore over connections of its own (one per server in the snapshot), on exit and with --restore
+pub async fn restore_devices(client_name: &str) -> Result<(), Box<dyn Error>> {
+    let mut snapshot = load_snapshot();
+    let mut servers: Vec<String> = snapshot
+        .controllers
+        .iter()
+        .map(|saved| saved.server.clone())
+        .collect();
+    servers.sort();
+    servers.dedup();
+
+    for server in &servers {
+        let client = match connect_openrgb(server, client_name).await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("{e}, its devices stay in the snapshot");
+                continue;
+            }
+        };
+        let controllers: Vec<Arc<Controller>> = client
+            .get_all_controllers()
+            .await?
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        restore_snapshot(&mut snapshot, server, &controllers).await?;
+    }
+
+    if snapshot.controllers.is_empty() {
+        if let Some(path) = state_file_path(SNAPSHOT_FILE_NAME) {
+            // Nothing was saved when the file isn't there
+            let _ = fs::remove_file(path);
+        }
+    } else {
+        let names: Vec<&str> = snapshot
+            .controllers
+            .iter()
+            .map(|saved| saved.name.as_str())
+            .collect();
+        warn!(
+            "Could not restore {}, they are not connected",
+            names.join(", ")
+        );
+        save_snapshot(&snapshot);
+    }
+    Ok(())
+}
@@ -5,56 +5,128 @@ use std::{
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use css_color_parser::Color as CssColor;
+use css_color_parser::{Color as CssColor, ColorParseError};
 use dashmap::DashMap;
-use log::info;
-use openrgb2::{Color, Controller, Led, Zone, ZoneType};
-
-use crate::{consts::*, enq_keyboard_frame};
+use log::{info, warn};
+use openrgb2::{Color, Controller, Led, OpenRgbClient, Zone, ZoneType};
+
+use crate::{
+    colorspace::Rgb,
+    compositor::{Layer, LayerId},
+    config::{
+        current_config, FallbackRule, LayoutConfig, NotificationSettings, SubstrateConfig, Theme,
+        TransitionProfile,
+    },
+    consts::*,
+    geometry::{PhysicalLayout, PlacementConfig},
+    selector::KeySelector,
+};
 
 pub struct ZonedControllerInfo {
-    raw: Controller,
+    // Shared by all the zones of the controller that are in use
+    raw: Arc<Controller>,
     zone_id: usize,
+    // Index of the first led of the zone in the leds of the controller
+    led_offset: usize,
+    zone_name: String,
+    // OpenRGB server from openrgb.servers, None for the main one
+    server: Option<String>,
 
     pub width: usize,
     pub height: usize,
 
-    pub center_x: usize,
-    pub center_y: usize,
-
     pub total_leds: usize,
+
+    // Cell of every led, column and row counted from the top left
+    cells: Vec<Point>,
+    // Led in every cell (row-major), matrix maps have holes
+    grid: Vec<Option<usize>>,
+
+    // Where the leds really are in led (key) units, the cells unless there is a physical layout
+    positions: Vec<(f64, f64)>,
+    size: (f64, f64),
+    center: (f64, f64),
+    // Positions in the desk space shared by all devices, in led (key) units
+    desk_positions: Vec<(f64, f64)>,
 }
 
+// Marks a cell without a led in the matrix map
+const NO_LED: u32 = u32::MAX;
+
 impl ZonedControllerInfo {
     pub fn new(
-        controller: Controller,
+        controller: Arc<Controller>,
         zone_name: &str,
     ) -> Result<ZonedControllerInfo, Box<dyn Error>> {
         let (target_zone_id, target_zone) = controller
             .get_all_zones()
             .enumerate()
             .find(|(_, zone)| zone.name().eq(zone_name))
-            .expect("Zone {zone_name} not found in {id}");
+            .ok_or_else(|| format!("Zone '{zone_name}' not found in {}", controller.name()))?;
 
         let mut height = 1;
         let total_leds = target_zone.num_leds();
+        // The controller lists the leds of all its zones one zone after another
+        let led_offset = controller
+            .get_all_zones()
+            .take(target_zone_id)
+            .map(|zone| zone.num_leds())
+            .sum();
         let mut width = total_leds;
+        // Linear zones are a single row
+        let mut grid: Vec<Option<usize>> = (0..total_leds).map(Some).collect();
 
         if target_zone.zone_type().eq(&ZoneType::Matrix) {
             let zone_matrix = target_zone
                 .matrix()
-                .expect("Matrix missing for {zone_name}");
+                .ok_or_else(|| format!("Matrix missing for {zone_name}"))?;
             width = zone_matrix.num_columns();
             height = zone_matrix.num_rows();
-            if zone_matrix.num_elements() != total_leds {
-                Err("zone_matrix.num_elements() != total_leds")?
+            grid = (0..height)
+                .flat_map(|row| (0..width).map(move |column| (row, column)))
+                .map(|(row, column)| match zone_matrix.get(row, column) {
+                    Some(&index) if index != NO_LED && (index as usize) < total_leds => {
+                        Some(index as usize)
+                    }
+                    _ => None,
+                })
+                .collect();
+        }
+
+        let mut cells: Vec<Option<Point>> = (0..total_leds).map(|_| None).collect();
+        for (cell, index) in grid.iter().enumerate() {
+            if let Some(index) = index {
+                cells[*index].get_or_insert(Point {
+                    x: cell % width,
+                    y: cell / width,
+                });
             }
         }
+        // Leds the map doesn't place go into extra rows below it, so they still show something
+        let missing: Vec<usize> = (0..total_leds)
+            .filter(|index| cells[*index].is_none())
+            .collect();
+        if !missing.is_empty() {
+            warn!(
+                "{} leds of {zone_name} are not in the matrix map",
+                missing.len()
+            );
+            width = width.max(1);
+            let rows = height;
+            height += missing.len().div_ceil(width);
+            grid.resize(width * height, None);
+            for (n, index) in missing.into_iter().enumerate() {
+                let (x, y) = (n % width, rows + n / width);
+                cells[index] = Some(Point { x, y });
+                grid[y * width + x] = Some(index);
+            }
+        }
+        let cells: Vec<Point> = cells.into_iter().flatten().collect();
 
         info!(
-            "Constructed a new controller: {} 
-                | zone name: {zone_name} 
-                | total_leds: {total_leds} 
+            "Constructed a new controller: {}
+                | zone name: {zone_name}
+                | total_leds: {total_leds}
                 | width: {width}, height: {height}
                 | center x: {}, center y: {}",
             controller.name(),
@@ -64,12 +136,25 @@ impl ZonedControllerInfo {
 
         Ok(ZonedControllerInfo {
             zone_id: target_zone_id,
+            led_offset,
+            zone_name: zone_name.to_owned(),
+            server: None,
             raw: controller,
             width,
             height,
-            center_x: width / 2,
-            center_y: height / 2,
             total_leds,
+            positions: cells
+                .iter()
+                .map(|cell| (cell.x as f64, cell.y as f64))
+                .collect(),
+            size: (width as f64, height as f64),
+            center: ((width / 2) as f64, (height / 2) as f64),
+            desk_positions: cells
+                .iter()
+                .map(|cell| (cell.x as f64, cell.y as f64))
+                .collect(),
+            cells,
+            grid,
         })
     }
 
@@ -77,27 +162,201 @@ impl ZonedControllerInfo {
         self.raw.get_zone(self.zone_id).unwrap()
     }
 
+    pub fn name(&self) -> &str {
+        self.raw.name()
+    }
+
+    pub fn zone_name(&self) -> &str {
+        &self.zone_name
+    }
+
+    pub fn server(&self) -> Option<&str> {
+        self.server.as_deref()
+    }
+
+    pub fn with_server(mut self, server: Option<String>) -> ZonedControllerInfo {
+        self.server = server;
+        self
+    }
+
+    // Leds of the zone, indexed from the start of the zone
     pub fn leds(&self) -> impl Iterator<Item = (usize, &Led)> {
-        self.raw.leds().iter().enumerate()
+        self.raw
+            .leds()
+            .iter()
+            .skip(self.led_offset)
+            .take(self.total_leds)
+            .enumerate()
+    }
+
+    // Leds of the top row from left to right, without offset_start leds at the start and offset_end at the end.
+    // Empty if the offsets don't leave anything
+    pub fn top_bar(&self, offset_start: usize, offset_end: usize) -> Vec<usize> {
+        let Some(top_row) = self.cells.iter().map(|cell| cell.y).min() else {
+            return Vec::new();
+        };
+        let row: Vec<usize> = (0..self.width)
+            .filter_map(|column| self.led_at(column, top_row))
+            .collect();
+        let end = row.len().saturating_sub(offset_end);
+        row.get(offset_start..end).unwrap_or_default().to_vec()
+    }
+
+    // Column and row of the led, counted from the top left
+    pub fn cell(&self, index: usize) -> &Point {
+        &self.cells[index.min(self.total_leds.saturating_sub(1))]
+    }
+
+    // Position of the led in led units, x from the left and y from the top
+    pub fn position(&self, index: usize) -> (f64, f64) {
+        self.positions[index.min(self.total_leds.saturating_sub(1))]
+    }
+
+    // Place the leds by a physical layout instead of the cells of the matrix,
+    // leds the layout doesn't have stay in their cells
+    pub fn with_physical_layout(mut self, layout: &PhysicalLayout) -> ZonedControllerInfo {
+        let placed = layout.place(self.leds().map(|(_, led)| led.name.as_str()));
+        let missing = placed.iter().filter(|position| position.is_none()).count();
+        if missing == self.total_leds {
+            warn!(
+                "The physical layout has none of the leds of {}",
+                self.raw.name()
+            );
+            return self;
+        }
+        if missing > 0 {
+            warn!(
+                "{missing} leds of {} are not in the physical layout",
+                self.raw.name()
+            );
+        }
+
+        let positions: Vec<(f64, f64)> = placed
+            .into_iter()
+            .zip(&self.positions)
+            .map(|(position, cell)| position.unwrap_or(*cell))
+            .collect();
+        let min_x = positions
+            .iter()
+            .map(|(x, _)| *x)
+            .fold(f64::INFINITY, f64::min);
+        let min_y = positions
+            .iter()
+            .map(|(_, y)| *y)
+            .fold(f64::INFINITY, f64::min);
+        self.positions = positions
+            .into_iter()
+            .map(|(x, y)| (x - min_x, y - min_y))
+            .collect();
+        let max_x = self.positions.iter().map(|(x, _)| *x).fold(0.0, f64::max);
+        let max_y = self.positions.iter().map(|(_, y)| *y).fold(0.0, f64::max);
+        self.size = (max_x + 1.0, max_y + 1.0);
+        self.center = (max_x / 2.0, max_y / 2.0);
+        self
     }
 
-    // Index of the led into xy coordinates
-    pub fn num2xy(&self, index: usize) -> Point {
-        let nc = index.clamp(0, self.total_leds);
-        let y = nc / self.width;
-        let x = nc - y * self.width;
-        Point {
-            x,
-            y: self.height - y - 1,
+    // Led in a cell, None for holes in the matrix and cells outside of it
+    pub fn led_at(&self, column: usize, row: usize) -> Option<usize> {
+        if column >= self.width || row >= self.height {
+            return None;
         }
+        self.grid[row * self.width + column]
+    }
+
+    // Leds in the (up to 8) cells around the led
+    pub fn neighbors(&self, index: usize) -> Vec<usize> {
+        let cell = self.cell(index);
+        let (x, y) = (cell.x as isize, cell.y as isize);
+        [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, 1),
+            (0, 1),
+            (1, 1),
+        ]
+        .into_iter()
+        .filter_map(|(dx, dy)| {
+            let (column, row) = (x + dx, y + dy);
+            if column < 0 || row < 0 {
+                return None;
+            }
+            self.led_at(column as usize, row as usize)
+        })
+        .collect()
+    }
+
+    // Distance between two leds in led units
+    pub fn distance(&self, from: usize, to: usize) -> f64 {
+        self.distance_to(from, self.position(to))
+    }
+
+    // Distance from a led to a point in led units
+    pub fn distance_to(&self, index: usize, (x, y): (f64, f64)) -> f64 {
+        let (led_x, led_y) = self.position(index);
+        ((led_x - x).powi(2) + (led_y - y).powi(2)).sqrt()
+    }
+
+    // Put the device on the desk, after the physical layout
+    pub fn with_placement(mut self, placement: &PlacementConfig) -> ZonedControllerInfo {
+        self.desk_positions = placement.place(&self.positions, self.height == 1);
+        self
+    }
+
+    // Position of the led in the desk space shared by all devices, in led units
+    pub fn desk_position(&self, index: usize) -> (f64, f64) {
+        self.desk_positions[index.min(self.total_leds.saturating_sub(1))]
+    }
+
+    // Center of the device on the desk
+    pub fn desk_center(&self) -> (f64, f64) {
+        let count = self.desk_positions.len().max(1) as f64;
+        let (x, y) = self
+            .desk_positions
+            .iter()
+            .fold((0.0, 0.0), |(sum_x, sum_y), (x, y)| (sum_x + x, sum_y + y));
+        (x / count, y / count)
+    }
+
+    // Led closest to a position in led units
+    pub fn nearest_led(&self, position: (f64, f64)) -> Option<usize> {
+        (0..self.total_leds).min_by(|a, b| {
+            self.distance_to(*a, position)
+                .total_cmp(&self.distance_to(*b, position))
+        })
+    }
+
+    // Center of the device in led units
+    pub fn center(&self) -> (f64, f64) {
+        self.center
+    }
+
+    // Width and height of the device in led units
+    pub fn size(&self) -> (f64, f64) {
+        self.size
     }
 }
 
-pub struct NotificationSettings {
-    pub color: Color,
-    pub important: bool,
-    pub flash_on_notify: bool,
-    pub flash_on_auto_close: Color,
+// Connect to the OpenRGB server at host:port and introduce ourselves
+pub async fn connect_openrgb(address: &str, name: &str) -> Result<OpenRgbClient, Box<dyn Error>> {
+    let mut client = OpenRgbClient::connect_to(address)
+        .await
+        .map_err(|e| format!("could not connect to OpenRGB at {address}: {e}"))?;
+    client.set_name(name).await?;
+    Ok(client)
+}
+
+// connect_openrgb that gives up after SERVER_CONNECT_TIMEOUT_MS, for the servers that aren't waited for
+pub async fn try_connect_openrgb(
+    address: &str,
+    name: &str,
+) -> Result<OpenRgbClient, Box<dyn Error>> {
+    let timeout = Duration::from_millis(SERVER_CONNECT_TIMEOUT_MS);
+    tokio::time::timeout(timeout, connect_openrgb(address, name))
+        .await
+        .map_err(|_| format!("OpenRGB at {address} did not answer"))?
 }
 
 pub struct Notification {
@@ -110,11 +369,12 @@ pub struct Notification {
 pub type ProgressMap = DashMap<String, (Color, f64)>;
 pub type ColorMap = DashMap<String, Color>;
 
+// Color with fractional channels (0..255)
 #[derive(Clone)]
 pub struct WideColor {
-    r: f64,
-    g: f64,
-    b: f64,
+    pub r: f64,
+    pub g: f64,
+    pub b: f64,
 }
 
 impl AddAssign<Color> for WideColor {
@@ -131,6 +391,75 @@ impl AddAssign<&Color> for WideColor {
     }
 }
 
+impl WideColor {
+    pub const BLACK: WideColor = WideColor {
+        r: 0.0,
+        g: 0.0,
+        b: 0.0,
+    };
+
+    // Add a color weighted by amount
+    pub fn add_scaled(&mut self, color: &Color, amount: f64) {
+        self.r += color.r as f64 * amount;
+        self.g += color.g as f64 * amount;
+        self.b += color.b as f64 * amount;
+    }
+
+    pub fn to_color(&self, divisor: f64) -> Color {
+        Color {
+            r: (self.r / divisor) as u8,
+            g: (self.g / divisor) as u8,
+            b: (self.b / divisor) as u8,
+        }
+    }
+
+    pub fn from_rgb([r, g, b]: Rgb) -> WideColor {
+        WideColor {
+            r: r * 255.0,
+            g: g * 255.0,
+            b: b * 255.0,
+        }
+    }
+}
+
+pub type WideFrame = Vec<WideColor>;
+
+// Turns high precision frames into 8 bit ones. The rounding error of every led is carried over into its next frame,
+// so a value between two levels shows as the right average over time instead of banding
+#[derive(Default)]
+pub struct Ditherer {
+    error: Vec<[f64; 3]>,
+}
+
+impl Ditherer {
+    pub fn dither(&mut self, frame: &[WideColor]) -> Frame {
+        let enabled = DITHERING.load(Ordering::Relaxed);
+        self.error.resize(frame.len(), [0.0; 3]);
+        frame
+            .iter()
+            .zip(self.error.iter_mut())
+            .map(|(color, error)| {
+                if !enabled {
+                    *error = [0.0; 3];
+                }
+                Color {
+                    r: quantize(color.r, &mut error[0]),
+                    g: quantize(color.g, &mut error[1]),
+                    b: quantize(color.b, &mut error[2]),
+                }
+            })
+            .collect()
+    }
+}
+
+fn quantize(value: f64, error: &mut f64) -> u8 {
+    let wanted = value + *error;
+    let out = wanted.round().clamp(0.0, 255.0);
+    // Don't build up error on clipped values
+    *error = (wanted - out).clamp(-1.0, 1.0);
+    out as u8
+}
+
 pub struct Point {
     pub x: usize,
     pub y: usize,
@@ -144,42 +473,18 @@ pub const fn u8_to_col(arr: [u8; 3]) -> Color {
     }
 }
 
-pub fn parse_hex(col: &str) -> Color {
-    let css_col = col.parse::<CssColor>().unwrap_or(TRANSPARENT_BLACK);
-    Color {
+pub fn parse_hex(col: &str) -> Result<Color, ColorParseError> {
+    let css_col = col.parse::<CssColor>()?;
+    Ok(Color {
         r: css_col.r,
         g: css_col.g,
         b: css_col.b,
-    }
+    })
 }
 
+// Interpolate in the configured color space
 pub fn lerp_color(from: &Color, to: &Color, progress: f64) -> Color {
-    let progress_01 = progress.clamp(0.0, 1.0);
-    Color {
-        r: (from.r as f64 * (1.0 - progress_01) + to.r as f64 * progress_01) as u8,
-        g: (from.g as f64 * (1.0 - progress_01) + to.g as f64 * progress_01) as u8,
-        b: (from.b as f64 * (1.0 - progress_01) + to.b as f64 * progress_01) as u8,
-    }
-}
-
-pub fn fade_into_frame(frame_to: &Frame, fade_time_ms: u32) {
-    // Calculate how many steps we need to take
-    let iterations = fade_time_ms / FRAME_DURATION_MS;
-    // don't cause a deadlock (by later inserting into the same map), copy the starting frame
-    let frame_from = KEYBOARD_LAST_FRAME.read().unwrap().clone();
-    // Iterate (+1 to immediately start changing, 0 = starting frame)
-    for i in 1..(iterations + 1) {
-        // Add frame to the queue
-        enq_keyboard_frame(
-            frame_from
-                .iter()
-                .zip(frame_to.iter())
-                .map(|(color_from, color_to)| -> Color {
-                    lerp_color(color_from, color_to, i as f64 / iterations as f64)
-                })
-                .collect(),
-        );
-    }
+    COLOR_SPACE.read().unwrap().lerp(from, to, progress)
 }
 
 pub fn get_timestamp() -> u128 {
@@ -190,6 +495,14 @@ pub fn get_timestamp() -> u128 {
         .as_millis()
 }
 
+pub fn current_keyboard() -> Arc<ZonedControllerInfo> {
+    KEYBOARD
+        .read()
+        .unwrap()
+        .clone()
+        .expect("Keyboard is accessed before it was found")
+}
+
 pub fn flash_color(
     keyboard_info: &Arc<ZonedControllerInfo>,
     color: Color,
@@ -197,10 +510,16 @@ pub fn flash_color(
     progress_map: &Arc<ProgressMap>,
     notifications: &Arc<RwLock<Vec<Notification>>>,
 ) -> bool {
+    let transitions = current_config().transitions.clone();
     // Store the target color right away
     KEYBOARD_FLASH_COLOR.store(color, Ordering::Relaxed);
-    // Animate! (300ms)
-    composite(keyboard_info, progress_map, notifications, Some(300));
+    // Animate!
+    composite(
+        keyboard_info,
+        progress_map,
+        notifications,
+        transitions.flash_in,
+    );
 
     tokio::spawn({
         let keyboard_info_clone = keyboard_info.clone();
@@ -220,7 +539,7 @@ pub fn flash_color(
                 &keyboard_info_clone,
                 &progress_map_clone,
                 &notifications_clone,
-                Some(300),
+                transitions.flash_out,
             );
         }
     });
@@ -231,32 +550,26 @@ pub fn composite(
     keyboard_info: &ZonedControllerInfo,
     progress_map: &ProgressMap,
     notifications_lock: &RwLock<Vec<Notification>>,
-    fade_time_ms: Option<u32>,
+    transition: TransitionProfile,
 ) -> bool {
     info!("COMPOSITE !");
     // Get the contents from the RwLock
     let notifications: std::sync::RwLockReadGuard<'_, Vec<Notification>> =
         notifications_lock.read().unwrap();
 
-    // This is the array that will hold colors of the loading bar at the top of the keyboard
-    // Initialise it to black initially
-    let mut top_bar: Vec<WideColor> = vec![
-        WideColor {
-            r: 0.0,
-            g: 0.0,
-            b: 0.0
-        };
-        keyboard_info.width
-    ];
-    // Start from the base frame
-    let mut new_frame = get_keyboard_base(keyboard_info);
+    // Leds of the top row that the loading bar, flashes and notifications are drawn on
+    let config = current_config();
+    let top_bar_config = &config.keyboard().top_bar;
+    let top_bar_leds =
+        keyboard_info.top_bar(top_bar_config.offset_start, top_bar_config.offset_end);
+    let top_bar_len = top_bar_leds.len();
+    let total_leds = keyboard_info.total_leds;
+
+    // Sum of the loading bar colors over the top bar and how much of each led they cover
+    let mut top_bar = vec![WideColor::BLACK; top_bar_len];
+    let mut coverage = vec![0.0; top_bar_len];
     // How many loading bars d we have
     let mut num_bars: usize = 0;
-    // How many colored(filled) leds do we have
-    let mut colored_leds: u32 = 0;
-
-    let corrected_top_row_len =
-        keyboard_info.width - KEYBOARD_COL_OFFSET_START - KEYBOARD_COL_OFFSET_END;
 
     for progress_tuple in progress_map {
         let color = progress_tuple.0;
@@ -270,81 +583,137 @@ pub fn composite(
         num_bars += 1;
 
         // Scale to fill the entire top row
-        let scaled_progress = progress * corrected_top_row_len as f64;
-        // Remove the floating part
-        let filled_leds = scaled_progress as usize;
-        // Update the number of colored leds (take maximum)
-        colored_leds = colored_leds.max(scaled_progress.ceil() as u32);
+        let scaled_progress = progress.min(1.0) * top_bar_len as f64;
 
-        // Calculate the progress of the last led (fade smoothly)
-        let last_led_progress = scaled_progress - filled_leds as f64;
-
-        // Add color to the bar
-        (0..filled_leds).for_each(|i| {
-            top_bar[i] += color;
-        });
-        // Lerp the last led, we can index into filled_leds because COL_OFFSET_END is 4 and top bar is always has some headroom
-        // TODO: Check properly
-        top_bar[filled_leds] += lerp_color(&new_frame[filled_leds], &color, last_led_progress);
+        // Full leds are covered completely, the last one partially (fade smoothly)
+        for (i, (sum, covered)) in top_bar.iter_mut().zip(coverage.iter_mut()).enumerate() {
+            let amount = (scaled_progress - i as f64).clamp(0.0, 1.0);
+            sum.add_scaled(&color, amount);
+            *covered += amount;
+        }
     }
 
     // Get the flash color
     let flash = KEYBOARD_FLASH_COLOR.load(Ordering::Relaxed);
-
-    if flash != BLACK {
-        // We need to flash
-        for i in 0..corrected_top_row_len {
-            // Fill the top bar
-            new_frame[i + KEYBOARD_COL_OFFSET_START] = flash;
-        }
-    } else {
-        // Normalise the color
-        for i in 0..colored_leds as usize {
-            new_frame[i + KEYBOARD_COL_OFFSET_START] = Color {
-                r: (top_bar[i].r / num_bars as f64) as u8,
-                g: (top_bar[i].g / num_bars as f64) as u8,
-                b: (top_bar[i].b / num_bars as f64) as u8,
-            };
+    let language_color = CURRENT_LANGUAGE_COLOR_MODIFIER.load(Ordering::Relaxed);
+    let idle_frame = get_idle_frame(keyboard_info);
+
+    // Every layer is retargeted from where it is right now, the render loop animates them
+    let mut layers = KEYBOARD_LAYERS.write().unwrap();
+
+    layers.set(
+        LayerId::Base,
+        Layer::new(&LayerId::Base, KEYBOARD_BASE_FRAME.read().unwrap().clone()),
+        transition,
+    );
+
+    match idle_frame {
+        Some((frame, brightness)) => {
+            layers.set(LayerId::Idle, Layer::new(&LayerId::Idle, frame), transition);
+            // Dimmed with a layer instead of darkening the colors, keeps the precision for dithering
+            layers.set(
+                LayerId::IdleDim,
+                Layer::dim(&LayerId::IdleDim, total_leds, brightness),
+                transition,
+            );
         }
-
-        for (index, notification) in (KEYBOARD_COL_OFFSET_START + 2..).zip(notifications.iter()) {
-            new_frame[index] = notification.settings.color;
+        None => {
+            layers.remove(&LayerId::Idle, transition);
+            layers.remove(&LayerId::IdleDim, transition);
         }
     }
 
-    let language_color = CURRENT_LANGUAGE_COLOR_MODIFIER.load(Ordering::Relaxed);
-    if language_color != BLACK {
-        for (index, led) in keyboard_info.leds() {
-            if CURRENT_LANGUAGE_COLOR_MARKER_KEYS
+    // Normalise the color, partially covered leds let the layers below show through
+    layers.set(
+        LayerId::Progress,
+        Layer::sparse(
+            &LayerId::Progress,
+            total_leds,
+            top_bar_leds
                 .iter()
-                .any(|key_substr| led.name.contains(key_substr))
-            {
-                new_frame[index] = language_color
-            }
-        }
-    }
-
-    // Finally fade into the new frame
-    fade_into_frame(&new_frame, fade_time_ms.unwrap_or(110));
+                .zip(top_bar.iter().zip(coverage.iter()))
+                .filter(|(_, (_, covered))| **covered > 0.0)
+                .map(|(index, (sum, covered))| {
+                    (*index, sum.to_color(*covered), covered / num_bars as f64)
+                }),
+        ),
+        transition,
+    );
+
+    // The flash covers the whole top bar, hiding the loading bars and notifications
+    layers.set(
+        LayerId::Flash,
+        Layer::sparse(
+            &LayerId::Flash,
+            total_leds,
+            top_bar_leds.iter().map(|index| (*index, flash, 1.0)),
+        )
+        .with_opacity(if flash != BLACK { 1.0 } else { 0.0 }),
+        transition,
+    );
+
+    layers.set(
+        LayerId::Notifications,
+        Layer::sparse(
+            &LayerId::Notifications,
+            total_leds,
+            top_bar_leds
+                .iter()
+                .skip(2)
+                .zip(notifications.iter())
+                .map(|(index, notification)| (*index, notification.settings.color, 1.0)),
+        )
+        .with_opacity(if flash != BLACK { 0.0 } else { 1.0 }),
+        transition,
+    );
+
+    layers.set(
+        LayerId::Language,
+        Layer::sparse(
+            &LayerId::Language,
+            total_leds,
+            LANGUAGE_MARKER_LEDS
+                .read()
+                .unwrap()
+                .iter()
+                .map(|index| (*index, language_color, 1.0)),
+        )
+        .with_opacity(if language_color != BLACK { 1.0 } else { 0.0 }),
+        transition,
+    );
     true
 }
 
-pub fn get_keyboard_base(keyboard_info: &ZonedControllerInfo) -> Frame {
-    let user_idle = USER_IDLE.load(Ordering::Relaxed);
+// Frame covering the keyboard while the user is idle or the screen is locked, and its brightness
+pub fn get_idle_frame(keyboard_info: &ZonedControllerInfo) -> Option<(Frame, f64)> {
+    let config = current_config();
+    let stage_number = USER_IDLE_STAGE.load(Ordering::Relaxed);
+    let idle_stage = config.idle.stage(stage_number);
     let screen_locked = SCREEN_LOCKED.load(Ordering::Relaxed);
-    if user_idle && screen_locked {
-        vec![IDLE_COLOR_LOCKED_SCREEN; keyboard_info.total_leds]
-    } else if user_idle {
-        KEYBOARD_IDLE_FRAME.read().unwrap().clone()
-    } else if screen_locked {
-        vec![LOCKED_SCREEN_COLOR; keyboard_info.total_leds]
-    } else {
-        KEYBOARD_BASE_FRAME.read().unwrap().clone()
+    let theme = THEME.read().unwrap();
+    match idle_stage {
+        Some(stage) => {
+            let frame = if screen_locked {
+                vec![theme.idle_locked_screen; keyboard_info.total_leds]
+            } else {
+                // Past the last stage it stays, like IdleConfig::stage
+                let frames = KEYBOARD_IDLE_FRAMES.read().unwrap();
+                frames
+                    .get(stage_number - 1)
+                    .or(frames.last())
+                    .cloned()
+                    .unwrap_or_default()
+            };
+            // Deeper stages dim their frame further
+            Some((frame, stage.brightness))
+        }
+        None if screen_locked => Some((vec![theme.locked_screen; keyboard_info.total_leds], 1.0)),
+        None => None,
     }
 }
 
 pub struct KeyMap<'a> {
-    pub keys: Vec<&'a str>,
+    pub keys: &'a KeySelector,
     pub color: Color,
 }
 
@@ -356,12 +725,9 @@ pub fn get_frame_by_key_names<'a>(
 ) -> Frame {
     leds.map(|(index, led)| -> Color {
         // Try to find the led in any keymap
-        let mapping = keymaps.iter().find(|keymap| -> bool {
-            keymap
-                .keys
-                .iter()
-                .any(|key_substr| led.name.contains(key_substr))
-        });
+        let mapping = keymaps
+            .iter()
+            .find(|keymap| -> bool { keymap.keys.matches(&led.name) });
         match mapping {
             Some(map) => map.color,
             None => fallback_function(led, index),
@@ -369,3 +735,79 @@ pub fn get_frame_by_key_names<'a>(
     })
     .collect()
 }
+
+fn fallback_color(
+    rule: &FallbackRule,
+    keyboard_info: &ZonedControllerInfo,
+    index: usize,
+    theme: &Theme,
+) -> Color {
+    let cell = keyboard_info.cell(index);
+    let (matches, color, default) = match rule {
+        FallbackRule::Color { color } => return color.resolve(theme),
+        FallbackRule::Rows {
+            rows,
+            color,
+            default,
+        } => (rows.contains(&cell.y), color, default),
+        FallbackRule::Columns {
+            columns,
+            color,
+            default,
+        } => (columns.contains(&cell.x), color, default),
+        FallbackRule::Index {
+            max_index,
+            color,
+            default,
+        } => (index <= *max_index, color, default),
+    };
+    match matches {
+        true => color.resolve(theme),
+        false => default.resolve(theme),
+    }
+}
+
+// Log key patterns of the layout that select nothing or too much
+pub fn layout_warnings(keyboard_info: &ZonedControllerInfo, layout: &LayoutConfig) -> Vec<String> {
+    let names: Vec<&str> = keyboard_info
+        .leds()
+        .map(|(_, led)| led.name.as_str())
+        .collect();
+    let mut warnings = Vec::new();
+    for (name, substrate) in [("active", &layout.active), ("idle", &layout.idle)] {
+        for (i, group) in substrate.keys.iter().enumerate() {
+            for warning in group.keys.warnings(&names, group.max_leds) {
+                warnings.push(format!("{name}.keys[{i}]: {warning}"));
+            }
+        }
+    }
+    for warning in layout.language_markers.warnings(&names, None) {
+        warnings.push(format!("language_markers: {warning}"));
+    }
+    for layer in &layout.layers {
+        for warning in layer.keys.warnings(&names, None) {
+            warnings.push(format!("layers.{}: {warning}", layer.name));
+        }
+    }
+    warnings
+}
+
+// Build a full keyboard frame from the key groups and fallback rule of the config
+pub fn substrate_frame(
+    keyboard_info: &ZonedControllerInfo,
+    substrate: &SubstrateConfig,
+    theme: &Theme,
+) -> Frame {
+    get_frame_by_key_names(
+        keyboard_info.leds(),
+        substrate
+            .keys
+            .iter()
+            .map(|group| KeyMap {
+                keys: &group.keys,
+                color: group.color.resolve(theme),
+            })
+            .collect(),
+        &|_: &Led, index: usize| fallback_color(&substrate.fallback, keyboard_info, index, theme),
+    )
+}
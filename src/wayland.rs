@@ -11,12 +11,12 @@ use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notification_v1::{
 };
 use wayland_protocols::ext::idle_notify::v1::client::ext_idle_notifier_v1::ExtIdleNotifierV1;
 
-use crate::consts::{IDLE_TIMEOUT_MS, USER_IDLE};
+use crate::consts::USER_IDLE_STAGE;
 
 struct AppState {
     _seat: WlSeat,
     _notifier: ExtIdleNotifierV1,
-    _notification: ExtIdleNotificationV1,
+    _notifications: Vec<ExtIdleNotificationV1>,
 }
 
 delegate_noop!(AppState: ignore WlSeat);
@@ -34,54 +34,59 @@ impl Dispatch<WlRegistry, GlobalListContents> for AppState {
     }
 }
 
-impl Dispatch<ExtIdleNotificationV1, ()> for AppState {
+// Each idle stage has its own notification, the user data is the stage number (from 1)
+impl Dispatch<ExtIdleNotificationV1, usize> for AppState {
     fn event(
         _state: &mut Self,
         _proxy: &ExtIdleNotificationV1,
         event: IdleNotificationEvent,
-        _data: &(),
+        stage: &usize,
         _conn: &Connection,
         _qh: &QueueHandle<Self>,
     ) {
         match event {
             IdleNotificationEvent::Idled => {
-                info!("Wayland: user idle");
-                USER_IDLE.store(true, Ordering::Relaxed);
+                info!("Wayland: user idle (stage {stage})");
+                USER_IDLE_STAGE.fetch_max(*stage, Ordering::Relaxed);
             }
             IdleNotificationEvent::Resumed => {
                 info!("Wayland: user active");
-                USER_IDLE.store(false, Ordering::Relaxed);
+                USER_IDLE_STAGE.fetch_min(stage - 1, Ordering::Relaxed);
             }
             _ => {}
         }
     }
 }
 
-fn run_wayland_monitor() -> Result<(), Box<dyn Error>> {
+fn run_wayland_monitor(stage_timeouts_ms: &[u32]) -> Result<(), Box<dyn Error>> {
     let conn = Connection::connect_to_env()?;
     let (globals, mut event_queue) = registry_queue_init::<AppState>(&conn)?;
     let qh = event_queue.handle();
 
     let seat: WlSeat = globals.bind(&qh, 1..=9, ())?;
     let notifier: ExtIdleNotifierV1 = globals.bind(&qh, 1..=2, ())?;
-    let notification = notifier.get_input_idle_notification(IDLE_TIMEOUT_MS, &seat, &qh, ());
+    let notifications = stage_timeouts_ms
+        .iter()
+        .enumerate()
+        .map(|(i, timeout)| notifier.get_input_idle_notification(*timeout, &seat, &qh, i + 1))
+        .collect();
 
     let mut state = AppState {
         _seat: seat,
         _notifier: notifier,
-        _notification: notification,
+        _notifications: notifications,
     };
 
-    info!("Wayland idle monitor started ({IDLE_TIMEOUT_MS}ms timeout)");
+    info!("Wayland idle monitor started (stage timeouts: {stage_timeouts_ms:?}ms)");
 
     loop {
         event_queue.blocking_dispatch(&mut state)?;
     }
 }
 
-pub fn spawn_wayland_monitor() {
-    std::thread::spawn(|| {
-        if let Err(e) = run_wayland_monitor() {
+pub fn spawn_wayland_monitor(stage_timeouts_ms: Vec<u32>) {
+    std::thread::spawn(move || {
+        if let Err(e) = run_wayland_monitor(&stage_timeouts_ms) {
             warn!("Wayland monitor unavailable: {e}");
         }
     });